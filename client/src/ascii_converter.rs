@@ -8,6 +8,8 @@ use opencv::{
 
 const ASCII_CHARS: &[char] = &[' ', '.', ',', ':', ';', '+', '*', '?', '%', 'S', '#', '@'];
 
+const FRAMES_PER_ROW: usize = 2;
+
 pub struct AsciiConverter {
     width: i32,
     height: i32,
@@ -56,7 +58,7 @@ impl AsciiConverter {
             .map(|(i, &ch)| (ch, i as u8))
             .collect();
 
-        let mut bytes = Vec::with_capacity((ascii.len() + 1) / 2);
+        let mut bytes = Vec::with_capacity(ascii.len().div_ceil(2));
 
         let mut chars = ascii.chars();
         while let Some(ch1) = chars.next() {
@@ -90,59 +92,71 @@ impl AsciiConverter {
         ascii
     }
 
-    pub fn merge_ascii_frames_side_by_side(
-        &self,
-        frame1: String,
-        frame2: String,
-        border: bool,
-    ) -> String {
-        let lines1: Vec<&str> = frame1.lines().collect();
-        let lines2: Vec<&str> = frame2.lines().collect();
+    pub fn merge_ascii_frames(&self, frames: &[String], border: bool) -> String {
+        frames
+            .chunks(FRAMES_PER_ROW)
+            .map(|row| self.merge_ascii_frames_side_by_side(row, border))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 
-        let max_lines = lines1.len().max(lines2.len());
+    pub fn merge_ascii_frames_side_by_side(&self, frames: &[String], border: bool) -> String {
+        let frame_lines: Vec<Vec<&str>> =
+            frames.iter().map(|frame| frame.lines().collect()).collect();
 
-        let width1 = lines1.iter().map(|l| l.len()).max().unwrap_or(0);
-        let width2 = lines2.iter().map(|l| l.len()).max().unwrap_or(0);
+        let max_lines = frame_lines
+            .iter()
+            .map(|lines| lines.len())
+            .max()
+            .unwrap_or(0);
+
+        let widths: Vec<usize> = frame_lines
+            .iter()
+            .map(|lines| lines.iter().map(|l| l.len()).max().unwrap_or(0))
+            .collect();
 
         let mut merged = String::new();
 
         if border {
             // Top border
-            merged.push('+');
-            merged.push_str(&"-".repeat(width1));
-            merged.push('+');
-            merged.push_str(&"-".repeat(width2));
-            merged.push('+');
+            merged.push_str(&horizontal_border(&widths));
             merged.push('\n');
         }
 
         for i in 0..max_lines {
-            let line1 = lines1.get(i).copied().unwrap_or("");
-            let line2 = lines2.get(i).copied().unwrap_or("");
+            let cells: Vec<String> = frame_lines
+                .iter()
+                .zip(&widths)
+                .map(|(lines, width)| {
+                    format!(
+                        "{:<width$}",
+                        lines.get(i).copied().unwrap_or(""),
+                        width = width
+                    )
+                })
+                .collect();
 
             if border {
                 merged.push('|');
-                merged.push_str(&format!("{:<width$}", line1, width = width1));
-                merged.push('|');
-                merged.push_str(&format!("{:<width$}", line2, width = width2));
+                merged.push_str(&cells.join("|"));
                 merged.push('|');
             } else {
-                merged.push_str(&format!("{:<width$}", line1, width = width1));
-                merged.push(' ');
-                merged.push_str(&format!("{:<width$}", line2, width = width2));
+                merged.push_str(&cells.join(" "));
             }
             merged.push('\n');
         }
 
         if border {
             // Bottom border
-            merged.push('+');
-            merged.push_str(&"-".repeat(width1));
-            merged.push('+');
-            merged.push_str(&"-".repeat(width2));
-            merged.push('+');
+            merged.push_str(&horizontal_border(&widths));
         }
 
         merged
     }
 }
+
+fn horizontal_border(widths: &[usize]) -> String {
+    let segments: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+
+    format!("+{}+", segments.join("+"))
+}
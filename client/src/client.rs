@@ -4,22 +4,30 @@ use crossterm::{
     terminal::{Clear, ClearType},
 };
 use shared::{
-    ADD_USER_TO_CLIENT_BYTE, DENY_CALL_BYTE, END_CALL_BYTE, HELLO_FROM_CLIENT_BYTE,
-    HELLO_FROM_SERVER_BYTE, REMOVE_USER_FROM_CLIENT_BYTE, REQUEST_CALL_BYTE,
-    REQUEST_CALL_STREAM_ID_BYTE, SEND_CALL_STREAM_ID_BYTE, START_CALL_BYTE,
-    USERNAME_ALREADY_TAKEN_BYTE, receive_command_from_stream, send_command_to_stream,
+    Capabilities, Command, CommandReader, FRAME_ENCODING_PACKED_ASCII, PROTOCOL_VERSION, Presence,
+    e2e::{FrameCipher, KeyExchange, PeerChannel},
+    identity::Identity,
+    media::{
+        DEFAULT_REASSEMBLY_TIMEOUT, FrameReassembler, MAX_MEDIA_DATAGRAM_SIZE,
+        MAX_MEDIA_PACKET_SIZE, MEDIA_FLAG_REGISTER, MEDIA_FLAG_REGISTERED, MEDIA_KEY_LEN,
+        MediaHeader, SID_LEN, control_packet, datagram_sid, fragment_frame, open_datagram,
+        seal_datagram,
+    },
+    send_command_to_stream,
+    tls::{self, TlsConnector, TlsStream, rustls::ClientConfig},
 };
 use std::{
+    collections::{BTreeMap, HashMap},
     error::Error,
-    io::{ErrorKind, Write, stdout},
+    io::{Write, stdout},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite},
     net::{TcpStream, UdpSocket},
     sync::Mutex,
-    time::sleep,
+    time::{Instant, sleep, sleep_until, timeout_at},
 };
 
 use crate::ascii_converter::AsciiConverter;
@@ -30,64 +38,192 @@ use opencv::{
 };
 
 const PROMPT_STRING: &str = "> ";
+const ACCEPT_CALL_PROMPT_STRING: &str = "Would you like to accept? (y/n): ";
+
+const MEDIA_REGISTRATION_ATTEMPTS: usize = 5;
+const MEDIA_REGISTRATION_RETRY_INTERVAL: Duration = Duration::from_millis(500);
 
 const WIDTH: i32 = 90;
 const HEIGHT: i32 = 28;
 
-pub struct Client {
-    tcp_stream: TcpStream,
+const CLIENT_CAPABILITIES: Capabilities = Capabilities {
+    frame_encodings: FRAME_ENCODING_PACKED_ASCII,
+    color: false,
+    max_width: WIDTH as u16,
+    max_height: HEIGHT as u16,
+};
+
+struct RemoteParticipant {
     username: String,
+    frame_cipher: FrameCipher,
+    reassembler: FrameReassembler,
+    frame: Option<String>,
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Option<String>,
+    pub identity: Option<Identity>,
+    pub register: bool,
+}
+
+pub struct Client<S> {
+    tcp_stream: S,
+    credentials: Credentials,
     server_udp_addr: String,
     auto_accept_calls: bool,
     border: bool,
+    heartbeat_timeout: Duration,
 }
-impl Client {
+impl Client<TcpStream> {
     pub async fn new(
         tcp_addr: String,
         udp_addr: String,
-        username: String,
+        credentials: Credentials,
         auto_accept_calls: bool,
         border: bool,
-    ) -> Result<Client, Box<dyn Error + Send + Sync>> {
+        heartbeat_timeout: Duration,
+    ) -> Result<Client<TcpStream>, Box<dyn Error + Send + Sync>> {
         Ok(Self {
             tcp_stream: TcpStream::connect(tcp_addr).await?,
-            username,
+            credentials,
             server_udp_addr: udp_addr,
             auto_accept_calls,
             border,
+            heartbeat_timeout,
         })
     }
+}
 
-    pub async fn run(&mut self) -> Result<Option<()>, Box<dyn Error + Send + Sync>> {
-        send_command_to_stream(
-            HELLO_FROM_CLIENT_BYTE,
-            Some(self.username.clone()),
-            &mut self.tcp_stream,
-        )
-        .await?;
+impl Client<TlsStream<TcpStream>> {
+    pub async fn new_tls(
+        tcp_addr: String,
+        udp_addr: String,
+        credentials: Credentials,
+        auto_accept_calls: bool,
+        border: bool,
+        heartbeat_timeout: Duration,
+        tls_config: Arc<ClientConfig>,
+    ) -> Result<Client<TlsStream<TcpStream>>, Box<dyn Error + Send + Sync>> {
+        let server_name = tls::server_name(&tcp_addr)?;
+        let tcp_stream = TcpStream::connect(tcp_addr).await?;
+
+        Ok(Self {
+            tcp_stream: TlsConnector::from(tls_config)
+                .connect(server_name, tcp_stream)
+                .await?,
+            credentials,
+            server_udp_addr: udp_addr,
+            auto_accept_calls,
+            border,
+            heartbeat_timeout,
+        })
+    }
+}
 
-        match receive_command_from_stream(&mut self.tcp_stream).await? {
-            Some((cmd, _)) => match cmd {
-                HELLO_FROM_SERVER_BYTE => {
-                    print_startup_message(self.username.clone())?;
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    pub async fn run(&mut self) -> Result<Option<()>, Box<dyn Error + Send + Sync>> {
+        let (read_half, mut write_half) = tokio::io::split(&mut self.tcp_stream);
+        let mut reader = CommandReader::new(read_half);
+        let stream = &mut write_half;
+
+        if self.credentials.register {
+            let password = self
+                .credentials
+                .password
+                .clone()
+                .ok_or("A password is required to register")?;
+
+            send_command_to_stream(
+                &Command::Register {
+                    username: self.credentials.username.clone(),
+                    password,
+                },
+                stream,
+            )
+            .await?;
+
+            match reader.receive().await? {
+                Some(Command::Registered(username)) => {
+                    println!("Registered account {}", username);
+                    self.credentials.register = false;
                 }
-                USERNAME_ALREADY_TAKEN_BYTE => {
-                    println!("Username {} already taken!", self.username);
+                Some(Command::Error { reason, .. }) => {
+                    println!("Could not register: {}", reason);
                     return Ok(None);
                 }
-                x => {
-                    return Err(format!("Invalid Response from server: {}", x).into());
+                Some(x) => {
+                    return Err(format!("Invalid Response from server: {:?}", x).into());
                 }
+                None => return Ok(None),
+            }
+        }
+
+        send_command_to_stream(
+            &Command::HelloFromClient {
+                version: PROTOCOL_VERSION,
+                capabilities: CLIENT_CAPABILITIES,
+                username: self.credentials.username.clone(),
+                password: self.credentials.password.clone(),
+                identity_key: self
+                    .credentials
+                    .identity
+                    .as_ref()
+                    .map(|identity| identity.public_key()),
             },
-            None => return Ok(None),
+            stream,
+        )
+        .await?;
+
+        loop {
+            match reader.receive().await? {
+                Some(command) => match command {
+                    Command::IdentityChallenge(challenge) => {
+                        let Some(identity) = &self.credentials.identity else {
+                            return Err(
+                                "Server sent an identity challenge we did not ask for".into()
+                            );
+                        };
+
+                        let signature =
+                            identity.sign_challenge(&challenge, &self.credentials.username);
+
+                        send_command_to_stream(&Command::IdentityProof(signature), stream).await?;
+                    }
+                    Command::HelloFromServer(_) => {
+                        print_startup_message(self.credentials.username.clone())?;
+                        break;
+                    }
+                    Command::HelloRejected(reason) => {
+                        println!("Server rejected connection: {}", reason);
+                        return Ok(None);
+                    }
+                    Command::UsernameAlreadyTaken => {
+                        println!("Username {} already taken!", self.credentials.username);
+                        return Ok(None);
+                    }
+                    x => {
+                        return Err(format!("Invalid Response from server: {:?}", x).into());
+                    }
+                },
+                None => return Ok(None),
+            }
         }
 
         let raw_stdin = tokio::io::stdin();
         let mut lines = tokio::io::BufReader::new(raw_stdin).lines();
 
-        let available_users: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-        let requesting_call_recipient = Arc::new(Mutex::new(None));
+        let available_users: Arc<Mutex<BTreeMap<String, Presence>>> =
+            Arc::new(Mutex::new(BTreeMap::new()));
+        // Whether the server vouches for each peer's account
+        let verified_peers: Arc<Mutex<HashMap<String, bool>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let requesting_call_recipient: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let incoming_call_recipient: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
         let call_recipient = Arc::new(Mutex::new(None));
+        let mut room = None;
+
+        let mut last_seen = Instant::now();
 
         print!("{}", PROMPT_STRING);
         stdout().flush()?;
@@ -95,7 +231,65 @@ impl Client {
         loop {
             tokio::select! {
 
-                result = lines.next_line(), if requesting_call_recipient.lock().await.is_none() => {
+                result = lines.next_line() => {
+
+                    let outgoing_call = requesting_call_recipient.lock().await.clone();
+
+                    if let Some(username) = outgoing_call {
+
+                        match result? {
+                            Some(line) if line.trim().eq_ignore_ascii_case("x") => {
+                                send_command_to_stream(&Command::CancelCall(username.clone()), stream).await?;
+
+                                requesting_call_recipient.lock().await.take();
+                                println!("Stopped calling {}.", username);
+
+                                print!("{}", PROMPT_STRING);
+                                stdout().flush()?;
+                            }
+                            Some(_) => println!("Calling {}... (x to hang up)", username),
+                            None => println!("No input received."),
+                        }
+
+                        continue;
+                    }
+
+                    let incoming_call = incoming_call_recipient.lock().await.clone();
+
+                    if let Some(username) = incoming_call {
+
+                        match result? {
+                            Some(line) => match line.trim().to_lowercase().as_str() {
+                                "yes" | "y" => {
+                                    send_command_to_stream(&Command::StartCall(username.clone()), stream).await?;
+
+                                    incoming_call_recipient.lock().await.take();
+                                    *call_recipient.lock().await = Some(username);
+
+                                    break;
+                                }
+                                "no" | "n" => {
+                                    send_command_to_stream(&Command::DenyCall(username), stream).await?;
+
+                                    incoming_call_recipient.lock().await.take();
+                                    println!("You answered NO.");
+                                }
+                                _ => {
+                                    println!("Invalid response.");
+                                    print!("{}", ACCEPT_CALL_PROMPT_STRING);
+                                    stdout().flush()?;
+                                    continue;
+                                }
+                            },
+                            None => {
+                                println!("No input received.");
+                            }
+                        }
+
+                        print!("{}", PROMPT_STRING);
+                        stdout().flush()?;
+                        continue;
+                    }
 
                     match result? {
                         Some(text) => {
@@ -110,8 +304,8 @@ impl Client {
                                         println!("No available users");
                                     } else {
                                         println!("Available users:");
-                                        for user in available_users_guard.iter() {
-                                            println!("  * {}", user);
+                                        for (user, presence) in available_users_guard.iter() {
+                                            println!("  * {} ({})", user, presence_name(*presence));
                                         }
                                     }
                                 }
@@ -123,16 +317,16 @@ impl Client {
 
                                     if let Some(username) = s.split_whitespace().nth(1) {
 
-                                        if available_users.lock().await.contains(&username.to_string()) {
+                                        if available_users.lock().await.contains_key(username) {
 
-                                            println!("Calling {}...", username);
-                                            send_command_to_stream(REQUEST_CALL_BYTE, Some(username.to_string()), &mut self.tcp_stream).await?;
+                                            println!("Calling {}... (x to hang up)", username);
+                                            send_command_to_stream(&Command::RequestCall(username.to_string()), stream).await?;
                                             *requesting_call_recipient.lock().await = Some(username.to_string());
                                             continue;
                                         }
                                         else {
 
-                                            if username != self.username {
+                                            if username != self.credentials.username {
 
                                                 println!("{} is not available.", username);
                                             }
@@ -147,6 +341,98 @@ impl Client {
                                         println!("Usage: c <username>");
                                     }
                                 }
+                                "r" => {
+
+                                    send_command_to_stream(&Command::ListRooms, stream).await?;
+                                    continue;
+                                }
+                                "j" => {
+
+                                    println!("Usage: j <room>");
+                                }
+                                s if s.starts_with("j ") => {
+
+                                    if let Some(name) = s.split_whitespace().nth(1) {
+
+                                        send_command_to_stream(&Command::JoinRoom(name.to_string()), stream).await?;
+                                        room = Some(name.to_string());
+                                        break;
+                                    }
+                                    else {
+
+                                        println!("Usage: j <room>");
+                                    }
+                                }
+                                "f" => {
+
+                                    send_command_to_stream(&Command::ListContacts, stream).await?;
+                                    continue;
+                                }
+                                "a" | "d" => {
+
+                                    println!("Usage: {} <username>", trimmed);
+                                }
+                                s if s.starts_with("a ") || s.starts_with("d ") => {
+
+                                    if let Some(username) = s.split_whitespace().nth(1) {
+
+                                        let command = if s.starts_with("a ") {
+                                            Command::AddContact(username.to_string())
+                                        } else {
+                                            Command::RemoveContact(username.to_string())
+                                        };
+
+                                        send_command_to_stream(&command, stream).await?;
+                                        continue;
+                                    }
+                                    else {
+
+                                        println!("Usage: {} <username>", &s[..1]);
+                                    }
+                                }
+                                "h" => {
+
+                                    send_command_to_stream(&Command::ListCallHistory, stream).await?;
+                                    continue;
+                                }
+                                "s" => {
+
+                                    send_command_to_stream(&Command::ListSettings, stream).await?;
+                                    continue;
+                                }
+                                s if s.starts_with("s ") => {
+
+                                    let mut parts = s.split_whitespace().skip(1);
+
+                                    if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+
+                                        send_command_to_stream(&Command::SetSetting { key: key.to_string(), value: value.to_string() }, stream).await?;
+                                        continue;
+                                    }
+                                    else {
+
+                                        println!("Usage: s <setting> <value>");
+                                    }
+                                }
+                                s if s == "p" || s.starts_with("p ") => {
+
+                                    let presence = match s.split_whitespace().nth(1) {
+                                        Some("available") => Some(Presence::Available),
+                                        Some("away") => Some(Presence::Away),
+                                        Some("dnd") => Some(Presence::DoNotDisturb),
+                                        _ => None,
+                                    };
+
+                                    if let Some(presence) = presence {
+
+                                        send_command_to_stream(&Command::SetPresence(presence), stream).await?;
+                                        println!("You are now {}.", presence_name(presence));
+                                    }
+                                    else {
+
+                                        println!("Usage: p <available|away|dnd>");
+                                    }
+                                }
                                 "q" => {
                                     println!("Quitting...");
                                     return Ok(None);
@@ -165,12 +451,14 @@ impl Client {
                     stdout().flush()?;
                 }
 
-                result = receive_command_from_stream(&mut self.tcp_stream) => {
+                result = reader.receive() => {
+
+                    last_seen = Instant::now();
 
                     match result? {
-                        Some((cmd, message)) => {
+                        Some(command) => {
 
-                            match handle_command(cmd, message, available_users.clone(), &mut lines, requesting_call_recipient.clone(), call_recipient.clone(), self.auto_accept_calls, &mut self.tcp_stream).await {
+                            match handle_command(command, available_users.clone(), verified_peers.clone(), requesting_call_recipient.clone(), incoming_call_recipient.clone(), call_recipient.clone(), self.auto_accept_calls, stream).await {
                                 Ok(Some(())) => continue,
                                 Ok(None) => break,
                                 Err(e) => {
@@ -179,227 +467,602 @@ impl Client {
                                 }
                             }
                         },
-                        None => return Ok(None),
+                        None => {
+                            println!("\nConnection to server lost");
+                            return Ok(None);
+                        }
                     }
                 }
+
+                _ = sleep_until(last_seen + self.heartbeat_timeout) => {
+
+                    println!("\nConnection to server lost: no heartbeat for {:?}", self.heartbeat_timeout);
+                    return Ok(None);
+                }
             }
         }
 
-        let call_recipient_guard = call_recipient.lock().await;
+        loop {
+            match &room {
+                Some(room) => println!("Joining room {}...", room),
+                None => {
+                    let call_recipient_guard = call_recipient.lock().await;
+
+                    let call_recipient = call_recipient_guard
+                        .as_ref()
+                        .ok_or("call_recipient not found")?;
+
+                    println!(
+                        "Connecting to {} ({})...",
+                        call_recipient,
+                        identity_name(verified_peers.lock().await.get(call_recipient).copied())
+                    );
+
+                    send_command_to_stream(
+                        &Command::RequestCallStreamId(call_recipient.to_string()),
+                        stream,
+                    )
+                    .await?;
+                }
+            }
 
-        let call_recipient = call_recipient_guard
-            .as_ref()
-            .ok_or("call_recipient not found")?;
+            let (sid, capabilities, media_key) = loop {
+                match reader.receive().await? {
+                    Some(Command::SendCallStreamId {
+                        sid,
+                        capabilities,
+                        media_key,
+                    }) => break (sid, capabilities, media_key),
+                    Some(Command::Error { reason, .. }) => {
+                        println!("Could not join call: {}", reason);
+                        return Ok(Some(()));
+                    }
+                    Some(Command::EndCall) => {
+                        println!("The call ended before it started");
+                        return Ok(Some(()));
+                    }
+                    Some(Command::Ping) => send_command_to_stream(&Command::Pong, stream).await?,
+                    Some(Command::Pong) => {}
+                    Some(Command::PeerIdentity { username, verified }) => {
+                        verified_peers.lock().await.insert(username, verified);
+                    }
+                    Some(
+                        Command::AddUserToClient(_)
+                        | Command::RemoveUserFromClient(_)
+                        | Command::PresenceChanged { .. },
+                    ) => {}
+                    // Leftovers from a call that was just hung up to answer a waiting one
+                    Some(
+                        Command::ParticipantLeft(_)
+                        | Command::CallPublicKey { .. }
+                        | Command::CallSenderKey { .. },
+                    ) => {}
+                    // Like the lobby, an unexpected command is not worth ending the session
+                    Some(x) => eprintln!("Error handling command: Invalid command {:?}", x),
+                    None => {
+                        println!("Connection to server lost");
+                        return Ok(None);
+                    }
+                }
+            };
 
-        println!("Connecting to {}...", call_recipient);
+            let key_exchange = KeyExchange::new(rand::random());
 
-        send_command_to_stream(
-            REQUEST_CALL_STREAM_ID_BYTE,
-            Some(call_recipient.to_string()),
-            &mut self.tcp_stream,
-        )
-        .await?;
+            let sender_key = rand::random();
+            let frame_cipher = FrameCipher::new(&sender_key);
 
-        let mut buf = [0; 5];
+            let mut peer_channels: HashMap<String, PeerChannel> = HashMap::new();
+            let mut sids_to_participants: HashMap<[u8; SID_LEN], RemoteParticipant> =
+                HashMap::new();
 
-        self.tcp_stream.read_exact(&mut buf).await?;
+            let mut status = None;
+            let mut stdin_open = true;
 
-        if buf[0] != SEND_CALL_STREAM_ID_BYTE {
-            return Err(format!("Invalid command {}", buf[0]).into());
-        }
+            last_seen = Instant::now();
 
-        let sid: [u8; 4] = buf[1..5].try_into()?;
+            let udp_socket = UdpSocket::bind("0.0.0.0:0").await?;
 
-        let udp_socket = UdpSocket::bind("0.0.0.0:0").await?;
+            // Drop datagrams that do not come from the server address
+            udp_socket.connect(&self.server_udp_addr).await?;
 
-        let mut tcp_buf = [0; 1];
-        let mut udp_buf = [0; 4840];
+            let mut udp_buf = [0; SID_LEN + MAX_MEDIA_PACKET_SIZE];
 
-        let mut cam = VideoCapture::new(0, CAP_ANY)?;
+            let mut next_sequence: u32 = 0;
+            let mut next_frame_id: u32 = 0;
 
-        if !cam.is_opened()? {
-            eprintln!("Error: Could not open camera");
-            return Ok(None);
-        }
+            if register_media_path(&udp_socket, &sid, &media_key, &mut next_sequence).await? {
+                println!("Media path established");
+            } else {
+                println!(
+                    "Media path failed: the server did not answer over UDP after {} attempts, it may be blocked by a firewall",
+                    MEDIA_REGISTRATION_ATTEMPTS
+                );
+            }
 
-        let ascii_converter = AsciiConverter::new(WIDTH, HEIGHT);
+            let mut cam = VideoCapture::new(0, CAP_ANY)?;
 
-        println!("Starting camera ASCII feed... Press Ctrl+C to exit");
-        println!("Camera initialized successfully!");
+            if !cam.is_opened()? {
+                eprintln!("Error: Could not open camera");
+                return Ok(None);
+            }
 
-        let mut frame = Mat::default();
+            let ascii_converter = AsciiConverter::new(
+                capabilities.max_width as i32,
+                capabilities.max_height as i32,
+            );
 
-        let user_camera_frame_string = Arc::new(Mutex::new(None));
+            println!("Starting camera ASCII feed... Press Ctrl+C to exit");
+            println!("Camera initialized successfully!");
+            println!("Type i <username> to invite someone into the call, or h to hang up");
 
-        loop {
-            tokio::select! {
+            let mut waiting_caller = None;
+            let mut answered_waiting_call = false;
+
+            let mut frame = Mat::default();
+
+            let user_camera_frame_string = Arc::new(Mutex::new(None));
+
+            loop {
+                tokio::select! {
 
-                result = self.tcp_stream.read(&mut tcp_buf) => {
+                    result = reader.receive() => {
 
-                    match result {
-                        Ok(0) => break,
-                        Ok(_) => {
-                            match tcp_buf[0] {
-                                END_CALL_BYTE => break,
-                                _ => continue
+                        last_seen = Instant::now();
+
+                        match result? {
+                            Some(Command::EndCall) => break,
+                            Some(Command::Ping) => send_command_to_stream(&Command::Pong, stream).await?,
+                            Some(Command::ParticipantJoined(username)) => {
+
+                                send_command_to_stream(&Command::CallPublicKey { username: username.clone(), public_key: key_exchange.public_key() }, stream).await?;
+
+                                let verified = verified_peers.lock().await.get(&username).copied();
+
+                                status = Some(format!("{} ({}) joined the call", username, identity_name(verified)));
                             }
-                        },
-                        Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
-                        Err(e) => return Err(e.into()),
-                    }
-                }
+                            Some(Command::PeerIdentity { username, verified }) => {
 
-                result = udp_socket.recv(&mut udp_buf) => {
+                                verified_peers.lock().await.insert(username, verified);
+                            }
+                            Some(Command::ParticipantLeft(username)) => {
 
-                    let n = result?;
+                                peer_channels.remove(&username);
+                                sids_to_participants.retain(|_, participant| participant.username != username);
 
-                    let other_user_camera_frame_str = AsciiConverter::bytes_to_ascii_frame(&udp_buf[0..n]);
+                                status = Some(format!("{} left the call", username));
+                            }
+                            Some(Command::CallPublicKey { username, public_key }) => {
 
-                    execute!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
-                    stdout().flush()?;
+                                match key_exchange.agree(public_key) {
+                                    Ok(peer_channel) => {
+                                        let sealed_key = peer_channel.seal_sender_key(&sid, &sender_key)?;
+
+                                        send_command_to_stream(&Command::CallSenderKey { username: username.clone(), sealed_key }, stream).await?;
+
+                                        peer_channels.insert(username, peer_channel);
+                                    }
+                                    Err(e) => status = Some(format!("Key exchange with {} failed: {}", username, e)),
+                                }
+                            }
+                            Some(Command::CallSenderKey { username, sealed_key }) => {
+
+                                let opened_key = match peer_channels.get(&username) {
+                                    Some(peer_channel) => peer_channel.open_sender_key(&sealed_key),
+                                    None => Err(format!("no key exchange with {}", username).into()),
+                                };
+
+                                match opened_key {
+                                    Ok((peer_sid, peer_sender_key)) => {
+                                        sids_to_participants.insert(peer_sid, RemoteParticipant {
+                                            username,
+                                            frame_cipher: FrameCipher::new(&peer_sender_key),
+                                            reassembler: FrameReassembler::new(DEFAULT_REASSEMBLY_TIMEOUT),
+                                            frame: None,
+                                        });
+                                    }
+                                    Err(e) => status = Some(format!("Could not read {}'s key: {}", username, e)),
+                                }
+                            }
+                            Some(Command::DenyCall(username)) => status = Some(format!("{} declined the invite", username)),
+                            Some(Command::CallWaiting(username)) => {
 
-                    let user_camera_frame_string_guard = user_camera_frame_string.lock().await;
+                                let verified = verified_peers.lock().await.get(&username).copied();
 
-                    if let Some(user_camera_frame_str) = (*user_camera_frame_string_guard).clone() {
+                                status = Some(format!("{} ({}) is calling, press w to hang up and answer", username, identity_name(verified)));
+                                waiting_caller = Some(username);
+                            }
+                            Some(Command::CallTimedOut(username)) => {
 
-                        println!("{}", ascii_converter.merge_ascii_frames_side_by_side(other_user_camera_frame_str.to_string(), user_camera_frame_str, self.border));
+                                status = if waiting_caller.take_if(|caller| *caller == username).is_some() {
+                                    Some(format!("Missed call from {}", username))
+                                } else {
+                                    Some(format!("{} didn't answer the invite", username))
+                                };
+                            }
+                            Some(Command::CancelCall(username)) => {
+
+                                if waiting_caller.take_if(|caller| *caller == username).is_some() {
+                                    status = Some(format!("Missed call from {}", username));
+                                }
+                            }
+                            Some(Command::Error { reason, .. }) => status = Some(reason),
+                            Some(_) => continue,
+                            None => {
+                                println!("Connection to server lost");
+                                return Ok(None);
+                            }
+                        }
                     }
-                    else {
 
-                        println!("{}", other_user_camera_frame_str);
+                    _ = sleep_until(last_seen + self.heartbeat_timeout) => {
+
+                        println!("Connection to server lost: no heartbeat for {:?}", self.heartbeat_timeout);
+                        return Ok(None);
                     }
-                }
 
-                _ = sleep(Duration::from_millis(3)) => {
+                    result = lines.next_line(), if stdin_open => {
 
-                    cam.read(&mut frame)?;
+                        match result? {
+                            Some(text) => match text.trim() {
+                                "h" => {
 
-                    if frame.empty() {
-                        eprintln!("Warning: Empty frame captured");
-                        continue;
+                                    if room.is_none() {
+                                        break;
+                                    }
+
+                                    send_command_to_stream(&Command::LeaveRoom, stream).await?;
+                                }
+                                "w" => {
+
+                                    let Some(caller) = waiting_caller.take() else {
+                                        status = Some("Nobody is waiting".to_string());
+                                        continue;
+                                    };
+
+                                    send_command_to_stream(&Command::StartCall(caller.clone()), stream).await?;
+
+                                    room = None;
+                                    *call_recipient.lock().await = Some(caller);
+                                    answered_waiting_call = true;
+
+                                    break;
+                                }
+                                s if s.starts_with("i ") => {
+
+                                    if let Some(username) = s.split_whitespace().nth(1) {
+
+                                        send_command_to_stream(&Command::InviteToCall(username.to_string()), stream).await?;
+
+                                        status = Some(format!("Inviting {}...", username));
+                                    }
+                                }
+                                _ => {
+                                    status = Some("Usage: i <username> to invite, w to answer a waiting call, h to hang up".to_string());
+                                }
+                            },
+                            None => stdin_open = false,
+                        }
                     }
 
-                    let message = ascii_converter.frame_to_ascii(&frame)?;
+                    result = udp_socket.recv(&mut udp_buf) => {
+
+                        let n = result?;
 
-                    *user_camera_frame_string.lock().await = Some(message.clone());
+                        let datagram = &udp_buf[0..n];
 
-                    let mut message_bytes = vec![];
-                    message_bytes.extend(&sid);
-                    message_bytes.extend(AsciiConverter::ascii_frame_to_bytes(message.clone()));
+                        let Some(peer_sid) = datagram_sid(datagram) else {
+                            continue;
+                        };
 
-                    udp_socket
-                        .send_to(&message_bytes, self.server_udp_addr.clone())
-                        .await?;
+                        // Frames from a participant whose key has not arrived yet are dropped
+                        let Some(participant) = sids_to_participants.get_mut(&peer_sid) else {
+                            continue;
+                        };
 
+                        let (header, payload) = match MediaHeader::parse(&datagram[SID_LEN..]) {
+                            Ok(parsed) => parsed,
+                            Err(e) => {
+                                eprintln!("Dropping media packet: {}", e);
+                                continue;
+                            }
+                        };
+
+                        let Some(frame_bytes) = participant.reassembler.push(&header, payload) else {
+                            continue;
+                        };
+
+                        let frame_bytes = match participant.frame_cipher.decrypt(header.frame_id, &frame_bytes) {
+                            Ok(frame_bytes) => frame_bytes,
+                            Err(e) => {
+                                eprintln!("Dropping media frame: {}", e);
+                                continue;
+                            }
+                        };
+
+                        participant.reassembler.mark_rendered(header.frame_id);
+
+                        participant.frame = Some(AsciiConverter::bytes_to_ascii_frame(&frame_bytes));
+
+                        execute!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
+                        stdout().flush()?;
+
+                        let mut participants: Vec<&RemoteParticipant> = sids_to_participants.values().collect();
+                        participants.sort_by(|a, b| a.username.cmp(&b.username));
+
+                        let mut frames: Vec<String> = participants.iter().filter_map(|participant| participant.frame.clone()).collect();
+
+                        if let Some(user_camera_frame_str) = user_camera_frame_string.lock().await.clone() {
+                            frames.push(user_camera_frame_str);
+                        }
+
+                        println!("{}", ascii_converter.merge_ascii_frames(&frames, self.border));
+
+                        let verified_peers = verified_peers.lock().await;
+
+                        for participant in participants {
+                            if let Some(peer_channel) = peer_channels.get(&participant.username) {
+                                let verified = verified_peers.get(&participant.username).copied();
+
+                                println!("{} ({}) fingerprint: {}", participant.username, identity_name(verified), peer_channel.fingerprint());
+                            }
+                        }
+
+                        if let Some(status) = &status {
+                            println!("{}", status);
+                        }
+                    }
+
+                    _ = sleep(Duration::from_millis(3)) => {
+
+                        cam.read(&mut frame)?;
+
+                        if frame.empty() {
+                            eprintln!("Warning: Empty frame captured");
+                            continue;
+                        }
+
+                        let message = ascii_converter.frame_to_ascii(&frame)?;
+
+                        *user_camera_frame_string.lock().await = Some(message.clone());
+
+                        let frame_bytes = AsciiConverter::ascii_frame_to_bytes(message.clone());
+
+                        let frame_bytes = frame_cipher.encrypt(next_frame_id, &frame_bytes)?;
+
+                        let packets = fragment_frame(&frame_bytes, next_frame_id, &mut next_sequence, 0)?;
+                        next_frame_id = next_frame_id.wrapping_add(1);
+
+                        for packet in packets {
+                            let message_bytes = seal_datagram(&sid, &media_key, &packet);
+
+                            udp_socket.send(&message_bytes).await?;
+                        }
+
+                    }
                 }
             }
+
+            if !answered_waiting_call {
+                break;
+            }
         }
 
-        return Ok(Some(()));
+        Ok(Some(()))
     }
 }
 
-async fn handle_command(
-    cmd: u8,
-    message: Option<String>,
-    available_users: Arc<Mutex<Vec<String>>>,
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::io::Stdin>>,
+#[allow(clippy::too_many_arguments)]
+async fn handle_command<W: AsyncWrite + Unpin>(
+    command: Command,
+    available_users: Arc<Mutex<BTreeMap<String, Presence>>>,
+    verified_peers: Arc<Mutex<HashMap<String, bool>>>,
     requesting_call_recipient: Arc<Mutex<Option<String>>>,
+    incoming_call_recipient: Arc<Mutex<Option<String>>>,
     call_recipient: Arc<Mutex<Option<String>>>,
     auto_accept_calls: bool,
-    stream: &mut TcpStream,
+    stream: &mut W,
 ) -> Result<Option<()>, Box<dyn Error + Send + Sync>> {
-    match cmd {
-        ADD_USER_TO_CLIENT_BYTE => match message {
-            Some(username) => {
-                available_users.lock().await.push(username.clone());
-            }
-            None => {
-                return Err("Invalid data".into());
-            }
-        },
-        REMOVE_USER_FROM_CLIENT_BYTE => match message {
-            Some(username) => {
-                available_users.lock().await.retain(|u| *u != username);
+    match command {
+        Command::AddUserToClient(username) => {
+            available_users
+                .lock()
+                .await
+                .entry(username)
+                .or_insert(Presence::Available);
+        }
+        Command::RemoveUserFromClient(username) => {
+            available_users.lock().await.remove(&username);
+        }
+        Command::PresenceChanged { username, presence } => {
+            available_users.lock().await.insert(username, presence);
+        }
+        // Large snapshots arrive in several parts
+        Command::PresenceSnapshot(presences) => {
+            available_users.lock().await.extend(presences);
+        }
+        Command::PeerIdentity { username, verified } => {
+            verified_peers.lock().await.insert(username, verified);
+        }
+        Command::RequestCall(username) => {
+            let verified = verified_peers.lock().await.get(&username).copied();
+
+            println!(
+                "\nIncoming call from {} ({})",
+                username,
+                identity_name(verified)
+            );
+
+            if auto_accept_calls {
+                send_command_to_stream(&Command::StartCall(username.clone()), stream).await?;
+
+                *call_recipient.lock().await = Some(username);
+
+                return Ok(None);
             }
-            None => {
-                return Err("Invalid data".into());
+
+            print!("{}", ACCEPT_CALL_PROMPT_STRING);
+            stdout().flush()?;
+
+            *incoming_call_recipient.lock().await = Some(username);
+        }
+
+        Command::DenyCall(username) => {
+            if let Some(requesting_call_recipient) = requesting_call_recipient.lock().await.take()
+                && username == requesting_call_recipient
+            {
+                println!("{} denied the call.", username);
+                print!("{}", PROMPT_STRING);
+                stdout().flush()?;
             }
-        },
-        REQUEST_CALL_BYTE => match message {
-            Some(username) => {
-                println!("\nIncoming call from {}", username);
+        }
 
-                if auto_accept_calls {
-                    send_command_to_stream(START_CALL_BYTE, Some(username.clone()), stream).await?;
+        Command::CallTimedOut(username) => {
+            if requesting_call_recipient
+                .lock()
+                .await
+                .take_if(|recipient| *recipient == username)
+                .is_some()
+            {
+                println!("\n{} didn't answer.", username);
+            } else if incoming_call_recipient
+                .lock()
+                .await
+                .take_if(|recipient| *recipient == username)
+                .is_some()
+            {
+                println!("\nMissed call from {}.", username);
+            }
 
-                    *call_recipient.lock().await = Some(username);
+            print!("{}", PROMPT_STRING);
+            stdout().flush()?;
+        }
 
-                    return Ok(None);
-                }
+        Command::CancelCall(username) => {
+            if incoming_call_recipient
+                .lock()
+                .await
+                .take_if(|recipient| *recipient == username)
+                .is_some()
+            {
+                println!("\n{} hung up.", username);
+            } else if requesting_call_recipient
+                .lock()
+                .await
+                .take_if(|recipient| *recipient == username)
+                .is_some()
+            {
+                println!("\n{} is no longer available.", username);
+            }
 
-                loop {
-                    print!("Would you like to accept? (y/n): ");
-                    stdout().flush()?;
+            print!("{}", PROMPT_STRING);
+            stdout().flush()?;
+        }
 
-                    if let Some(line) = lines.next_line().await? {
-                        match line.trim().to_lowercase().as_str() {
-                            "yes" | "y" => {
-                                send_command_to_stream(
-                                    START_CALL_BYTE,
-                                    Some(username.clone()),
-                                    stream,
-                                )
-                                .await?;
+        Command::Busy(username) => {
+            if requesting_call_recipient
+                .lock()
+                .await
+                .take_if(|recipient| *recipient == username)
+                .is_some()
+            {
+                println!("{} is busy.", username);
+                print!("{}", PROMPT_STRING);
+                stdout().flush()?;
+            }
+        }
+        Command::CallWaiting(username) => {
+            println!(
+                "{} is in another call, waiting for them to answer... (x to hang up)",
+                username
+            );
+        }
+        Command::StartCall(username) => {
+            *call_recipient.lock().await = Some(username);
+            return Ok(None);
+        }
 
-                                *call_recipient.lock().await = Some(username);
+        Command::Ping => {
+            send_command_to_stream(&Command::Pong, stream).await?;
+        }
 
-                                return Ok(None);
-                            }
-                            "no" | "n" => {
-                                send_command_to_stream(DENY_CALL_BYTE, Some(username), stream)
-                                    .await?;
-                                println!("You answered NO.");
+        Command::Pong => {}
 
-                                print!("{}", PROMPT_STRING);
-                                stdout().flush()?;
-                                break;
-                            }
-                            _ => println!("Invalid response."),
-                        }
-                    } else {
-                        println!("No input received.");
-                    }
+        Command::RoomList(rooms) => {
+            if rooms.is_empty() {
+                println!("No rooms");
+            } else {
+                println!("Rooms:");
+                for (room, participants) in rooms {
+                    println!("  * {} ({} in room)", room, participants);
                 }
             }
-            None => {
-                return Err("Invalid data".into());
-            }
-        },
-
-        DENY_CALL_BYTE => {
-            if let Some(username) = message {
-                if let Some(requesting_call_recipient) =
-                    requesting_call_recipient.lock().await.take()
-                {
-                    if username == requesting_call_recipient {
-                        println!("{} denied the call.", username);
-                        print!("{}", PROMPT_STRING);
-                        stdout().flush()?;
-                    }
-                }
+
+            print!("{}", PROMPT_STRING);
+            stdout().flush()?;
+        }
+
+        Command::ContactList(contacts) => {
+            if contacts.is_empty() {
+                println!("No contacts");
             } else {
-                return Err("Invalid data".into());
+                println!("Contacts:");
+                for (contact, online) in contacts {
+                    let status = if online { "online" } else { "offline" };
+                    println!("  * {} ({})", contact, status);
+                }
             }
+
+            print!("{}", PROMPT_STRING);
+            stdout().flush()?;
         }
 
-        START_CALL_BYTE => {
-            if let Some(username) = message {
-                *call_recipient.lock().await = Some(username);
+        Command::CallHistory(records) => {
+            if records.is_empty() {
+                println!("No calls yet");
             } else {
-                return Err("Invalid data".into());
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or(0);
+
+                println!("Recent calls:");
+                for record in records {
+                    let with = match (&record.room, record.peers.is_empty()) {
+                        (Some(room), _) => format!("room {}", room),
+                        (None, true) => "nobody".to_string(),
+                        (None, false) => record.peers.join(", "),
+                    };
+
+                    println!(
+                        "  * {} for {}, {} ago",
+                        with,
+                        format_duration(record.ended_at.saturating_sub(record.started_at)),
+                        format_duration(now.saturating_sub(record.ended_at))
+                    );
+                }
             }
-            return Ok(None);
+
+            print!("{}", PROMPT_STRING);
+            stdout().flush()?;
+        }
+
+        Command::Settings(settings) => {
+            println!("Settings:");
+            for (key, value) in settings {
+                println!("  * {} = {}", key, value);
+            }
+
+            print!("{}", PROMPT_STRING);
+            stdout().flush()?;
+        }
+
+        Command::Error { reason, .. } => {
+            println!("\nServer error: {}", reason);
+
+            requesting_call_recipient.lock().await.take();
+
+            print!("{}", PROMPT_STRING);
+            stdout().flush()?;
         }
 
         _ => {
@@ -407,7 +1070,70 @@ async fn handle_command(
         }
     }
 
-    return Ok(Some(()));
+    Ok(Some(()))
+}
+
+// Registers the UDP socket with the server and waits for its acknowledgement,
+// retrying since either datagram can be lost. Returns false when none came back.
+async fn register_media_path(
+    udp_socket: &UdpSocket,
+    sid: &[u8; SID_LEN],
+    media_key: &[u8; MEDIA_KEY_LEN],
+    next_sequence: &mut u32,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut buf = [0; MAX_MEDIA_DATAGRAM_SIZE];
+
+    for _ in 0..MEDIA_REGISTRATION_ATTEMPTS {
+        let packet = control_packet(MEDIA_FLAG_REGISTER, *next_sequence);
+        *next_sequence = next_sequence.wrapping_add(1);
+
+        udp_socket
+            .send(&seal_datagram(sid, media_key, &packet))
+            .await?;
+
+        let deadline = Instant::now() + MEDIA_REGISTRATION_RETRY_INTERVAL;
+
+        // Media from others can arrive first once the server knows us
+        while let Ok(result) = timeout_at(deadline, udp_socket.recv(&mut buf)).await {
+            let datagram = &buf[..result?];
+
+            if datagram_sid(datagram) == Some(*sid)
+                && let Ok(message) = open_datagram(media_key, datagram)
+                && let Ok((header, _)) = MediaHeader::parse(message)
+                && header.flags & MEDIA_FLAG_REGISTERED != 0
+            {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+fn presence_name(presence: Presence) -> &'static str {
+    match presence {
+        Presence::Available => "available",
+        Presence::InCall => "in a call",
+        Presence::Away => "away",
+        Presence::DoNotDisturb => "do not disturb",
+    }
+}
+
+// Peers the server never vouched for are treated as anonymous
+fn identity_name(verified: Option<bool>) -> &'static str {
+    match verified {
+        Some(true) => "verified account",
+        _ => "anonymous, identity not verified",
+    }
 }
 
 fn print_startup_message(username: String) -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -419,8 +1145,16 @@ fn print_startup_message(username: String) -> Result<(), Box<dyn Error + Send +
     println!("Commands available:");
     println!("  l - List all active users");
     println!("  c - Connect to a user");
+    println!("  r - List all rooms");
+    println!("  j - Join a room");
+    println!("  f - List your contacts");
+    println!("  a - Add a contact");
+    println!("  d - Remove a contact");
+    println!("  h - Show your recent calls");
+    println!("  s - Show or change settings");
+    println!("  p - Set your presence");
     println!("  q - Quit the program");
     println!();
 
-    return Ok(());
+    Ok(())
 }
@@ -1,15 +1,27 @@
 use clap::{ArgAction, Parser};
-use client::Client;
-use shared::{TCP_PORT, UDP_PORT};
+use client::{Client, Credentials};
+use shared::{
+    DEFAULT_HEARTBEAT_TIMEOUT, MAX_USERNAME_LEN, TCP_PORT, UDP_PORT,
+    identity::{IDENTITY_SEED_LEN, Identity},
+    is_valid_username, tls,
+};
 use std::{
+    env,
     error::Error,
-    io::{Write, stdout},
+    fs,
+    io::{ErrorKind, Write, stdout},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 use tokio::io::{self, AsyncBufReadExt};
 
 mod ascii_converter;
 mod client;
 
+const PASSWORD_ENV: &str = "FACETIME_PASSWORD";
+const CONFIG_DIR_NAME: &str = "facetime-v3";
+const IDENTITY_FILE_NAME: &str = "identity.key";
+
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long)]
@@ -18,32 +30,125 @@ struct Args {
     #[arg(short, long, default_value = "facetime-v3.fly.dev")]
     server_address: String,
 
+    #[arg(long, default_value_t = TCP_PORT)]
+    tcp_port: u16,
+
+    #[arg(long, default_value_t = UDP_PORT)]
+    udp_port: u16,
+
     #[arg(short, long, action = ArgAction::SetTrue)]
     auto_accept_calls: bool,
 
     #[arg(short, long, action = ArgAction::SetTrue)]
     border: bool,
+
+    #[arg(long, default_value_t = DEFAULT_HEARTBEAT_TIMEOUT.as_secs())]
+    heartbeat_timeout_secs: u64,
+
+    #[arg(long, action = ArgAction::SetTrue)]
+    tls: bool,
+
+    /// Trust only this PEM encoded CA when verifying the server
+    #[arg(long)]
+    tls_ca: Option<String>,
+
+    /// Accept only the server certificate with this SHA-256 fingerprint
+    #[arg(long)]
+    tls_pin: Option<String>,
+
+    /// Log in with a password, read from FACETIME_PASSWORD or prompted for
+    #[arg(long, action = ArgAction::SetTrue)]
+    login: bool,
+
+    /// Create an account before logging in
+    #[arg(long, action = ArgAction::SetTrue)]
+    register: bool,
+
+    /// Identity key to sign in with, created on first use
+    #[arg(long)]
+    identity_file: Option<PathBuf>,
+
+    /// Connect without an identity key
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_identity: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let args = Args::parse();
 
+    let tls_config = if args.tls || args.tls_ca.is_some() || args.tls_pin.is_some() {
+        Some(tls::client_config(
+            args.tls_ca.as_deref(),
+            args.tls_pin.as_deref(),
+        )?)
+    } else {
+        None
+    };
+
+    let password = match env::var(PASSWORD_ENV) {
+        Ok(password) => Some(password),
+        Err(_) if args.login || args.register => Some(rpassword::prompt_password("Password: ")?),
+        Err(_) => None,
+    };
+
+    let identity = if args.no_identity {
+        None
+    } else {
+        Some(load_or_create_identity(args.identity_file.clone())?)
+    };
+
+    let mut register = args.register;
+
     loop {
         let username = get_username(args.username.clone()).await?;
 
-        let tcp_addr = format!("{}:{}", args.server_address, TCP_PORT);
-        let udp_addr = format!("{}:{}", args.server_address, UDP_PORT);
-
-        let mut client = Client::new(
-            tcp_addr,
-            udp_addr,
+        let credentials = Credentials {
             username,
-            args.auto_accept_calls,
-            args.border,
-        )
-        .await?;
-        match client.run().await? {
+            password: password.clone(),
+            identity: identity.clone(),
+            register,
+        };
+
+        // Only the first connection registers, reconnects after a call just log in
+        register = false;
+
+        let tcp_addr = format!("{}:{}", args.server_address, args.tcp_port);
+        let udp_addr = format!("{}:{}", args.server_address, args.udp_port);
+
+        let heartbeat_timeout = Duration::from_secs(args.heartbeat_timeout_secs);
+
+        let result = match &tls_config {
+            Some(tls_config) => {
+                Client::new_tls(
+                    tcp_addr,
+                    udp_addr,
+                    credentials,
+                    args.auto_accept_calls,
+                    args.border,
+                    heartbeat_timeout,
+                    tls_config.clone(),
+                )
+                .await?
+                .run()
+                .await?
+            }
+            None => {
+                Client::new(
+                    tcp_addr,
+                    udp_addr,
+                    credentials,
+                    args.auto_accept_calls,
+                    args.border,
+                    heartbeat_timeout,
+                )
+                .await?
+                .run()
+                .await?
+            }
+        };
+
+        match result {
             Some(()) => continue,
             None => break,
         }
@@ -52,19 +157,86 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     return Ok(());
 }
 
+fn load_or_create_identity(
+    identity_file: Option<PathBuf>,
+) -> Result<Identity, Box<dyn Error + Send + Sync>> {
+    let path = match identity_file {
+        Some(path) => path,
+        None => dirs::config_dir()
+            .ok_or("Could not find a config directory, pass --identity-file")?
+            .join(CONFIG_DIR_NAME)
+            .join(IDENTITY_FILE_NAME),
+    };
+
+    match fs::read(&path) {
+        Ok(bytes) => {
+            return Identity::from_bytes(&bytes)
+                .map_err(|e| format!("Invalid identity key {}: {}", path.display(), e).into());
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+    }
+
+    let identity = Identity::from_seed(rand::random::<[u8; IDENTITY_SEED_LEN]>());
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    write_private_file(&path, &identity.to_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    println!("Created a new identity key at {}", path.display());
+
+    Ok(identity)
+}
+
+#[cfg(unix)]
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?
+        .write_all(contents)
+}
+
 async fn get_username(
     cli_username: Option<String>,
 ) -> Result<String, Box<dyn Error + Send + Sync>> {
-    if let Some(name) = cli_username {
-        return Ok(name);
-    }
+    let name = match cli_username {
+        Some(name) => name,
+        None => {
+            print!("Enter username: ");
+            stdout().flush()?;
 
-    print!("Enter username: ");
-    stdout().flush()?;
+            let mut lines = io::BufReader::new(io::stdin()).lines();
+            match lines.next_line().await? {
+                Some(name) if !name.trim().is_empty() => name.trim().to_string(),
+                _ => return Err("No username provided".into()),
+            }
+        }
+    };
 
-    let mut lines = io::BufReader::new(io::stdin()).lines();
-    match lines.next_line().await? {
-        Some(name) if !name.trim().is_empty() => Ok(name),
-        _ => Err("No username provided".into()),
+    // The server would refuse it anyway, this just says why up front
+    if !is_valid_username(&name) {
+        return Err(format!(
+            "Usernames must be 1 to {} letters, digits, '-', '_' or '.'",
+            MAX_USERNAME_LEN
+        )
+        .into());
     }
+
+    Ok(name)
 }
@@ -0,0 +1,422 @@
+use std::{error::Error, sync::Arc};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use shared::identity::IDENTITY_PUBLIC_KEY_LEN;
+use tokio::{sync::Semaphore, task::spawn_blocking};
+
+use crate::storage::{Storage, blocking};
+
+const SALT_LEN: usize = 16;
+// Each Argon2 run holds about 19 MiB, so only a few run at once however many
+// clients register or log in together
+const MAX_CONCURRENT_HASHES: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Login {
+    Anonymous,
+    Authenticated,
+    Rejected(String),
+}
+
+pub struct Accounts {
+    storage: Arc<dyn Storage>,
+    require_accounts: bool,
+    allow_registration: bool,
+    hashing: Arc<Semaphore>,
+}
+
+impl Accounts {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        require_accounts: bool,
+        allow_registration: bool,
+    ) -> Accounts {
+        Accounts {
+            storage,
+            require_accounts,
+            allow_registration,
+            hashing: Arc::new(Semaphore::new(MAX_CONCURRENT_HASHES)),
+        }
+    }
+
+    pub fn allow_registration(&self) -> bool {
+        self.allow_registration
+    }
+
+    // Returns false when the username is already claimed, by a password or
+    // an identity key
+    pub async fn register(
+        &self,
+        username: &str,
+        password: String,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let user = {
+            let username = username.to_string();
+
+            blocking(&self.storage, move |storage| storage.user(&username)).await?
+        };
+
+        if user.is_some() {
+            return Ok(false);
+        }
+
+        // Argon2 is deliberately slow, keep it off the async workers
+        // The permit moves into the job so it is held until hashing finishes
+        let permit = self.hashing.clone().acquire_owned().await?;
+
+        let password_hash = spawn_blocking(move || {
+            let _permit = permit;
+
+            hash_password(&password)
+        })
+        .await??;
+
+        let username = username.to_string();
+
+        blocking(&self.storage, move |storage| {
+            storage.create_password(&username, &password_hash)
+        })
+        .await
+    }
+
+    // The identity key must already have been proven with a signed challenge
+    pub async fn login(
+        &self,
+        username: &str,
+        password: Option<String>,
+        identity_key: Option<[u8; IDENTITY_PUBLIC_KEY_LEN]>,
+    ) -> Result<Login, Box<dyn Error + Send + Sync>> {
+        let user = {
+            let username = username.to_string();
+
+            blocking(&self.storage, move |storage| storage.user(&username)).await?
+        };
+
+        let (password_hash, bound_key) = match user {
+            Some(user) => (user.password_hash, user.identity_key),
+            None => (None, None),
+        };
+
+        let password_verified = match (&password_hash, password) {
+            (Some(password_hash), Some(password)) => {
+                let password_hash = password_hash.clone();
+                let permit = self.hashing.clone().acquire_owned().await?;
+
+                let verified = spawn_blocking(move || {
+                    let _permit = permit;
+
+                    verify_password(&password, &password_hash)
+                })
+                .await?;
+
+                if !verified {
+                    return Ok(Login::Rejected("Invalid username or password".to_string()));
+                }
+
+                true
+            }
+            (None, Some(_)) => {
+                return Ok(Login::Rejected("Invalid username or password".to_string()));
+            }
+            (_, None) => false,
+        };
+
+        let key_verified = match (bound_key, identity_key) {
+            (Some(bound_key), Some(identity_key)) if bound_key == identity_key => true,
+            (Some(_), Some(_)) if password_hash.is_some() => {
+                return Ok(Login::Rejected(format!(
+                    "{} is bound to a different identity key, log in with --no-identity to use the password alone",
+                    username
+                )));
+            }
+            (Some(_), Some(_)) => {
+                return Ok(Login::Rejected(format!(
+                    "{} is bound to a different identity key",
+                    username
+                )));
+            }
+            (Some(_), None) if !password_verified => {
+                return Ok(Login::Rejected(format!(
+                    "{} requires its identity key",
+                    username
+                )));
+            }
+            (Some(_), None) => false,
+            // The first key to claim a username keeps it. Password accounts
+            // never take one here, the client sends its key by default and
+            // that would lock the account to whichever machine logged in first.
+            (None, Some(identity_key)) if self.allow_registration && password_hash.is_none() => {
+                let bound_key = {
+                    let username = username.to_string();
+
+                    blocking(&self.storage, move |storage| {
+                        storage.bind_identity_key(&username, &identity_key)
+                    })
+                    .await?
+                };
+
+                if bound_key != identity_key {
+                    return Ok(Login::Rejected(format!(
+                        "{} is bound to a different identity key",
+                        username
+                    )));
+                }
+
+                true
+            }
+            (None, _) => false,
+        };
+
+        let login = if password_verified || key_verified {
+            Login::Authenticated
+        } else if password_hash.is_some() {
+            Login::Rejected(format!("{} requires a password", username))
+        } else if self.require_accounts {
+            Login::Rejected(
+                "This server requires an account, register before logging in".to_string(),
+            )
+        } else {
+            Login::Anonymous
+        };
+
+        Ok(login)
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; SALT_LEN]>())
+        .map_err(|e| format!("Failed to encode salt: {}", e))?;
+
+    let password_hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| format!("Failed to hash password: {}", e))?;
+
+    Ok(password_hash.to_string())
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed_hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    const USERNAME: &str = "alice";
+    const PASSWORD: &str = "correct horse";
+    const KEY: [u8; IDENTITY_PUBLIC_KEY_LEN] = [1; IDENTITY_PUBLIC_KEY_LEN];
+    const OTHER_KEY: [u8; IDENTITY_PUBLIC_KEY_LEN] = [2; IDENTITY_PUBLIC_KEY_LEN];
+
+    // Argon2 is slow in debug builds, hash once for every case
+    fn password_hash() -> &'static str {
+        static PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+
+        PASSWORD_HASH.get_or_init(|| hash_password(PASSWORD).unwrap())
+    }
+
+    fn accounts(has_password: bool, bound_key: Option<[u8; IDENTITY_PUBLIC_KEY_LEN]>) -> Accounts {
+        let storage = Arc::new(MemoryStorage::new());
+
+        if has_password {
+            assert!(storage.create_password(USERNAME, password_hash()).unwrap());
+        }
+
+        if let Some(bound_key) = bound_key {
+            storage.bind_identity_key(USERNAME, &bound_key).unwrap();
+        }
+
+        Accounts::new(storage, false, true)
+    }
+
+    fn rejected(reason: &str) -> Login {
+        Login::Rejected(reason.to_string())
+    }
+
+    #[tokio::test]
+    async fn login_checks_every_combination_of_password_and_key() {
+        let right = Some(PASSWORD.to_string());
+        let wrong = Some("wrong".to_string());
+        let invalid = rejected("Invalid username or password");
+        let requires_password = rejected("alice requires a password");
+        let requires_key = rejected("alice requires its identity key");
+        let other_key = rejected("alice is bound to a different identity key");
+        let other_key_with_password = rejected(
+            "alice is bound to a different identity key, log in with --no-identity to use the password alone",
+        );
+
+        // (has password, bound key, password sent, key sent, expected login)
+        let cases = [
+            (false, None, None, None, Login::Anonymous),
+            (false, None, None, Some(KEY), Login::Authenticated),
+            (false, None, right.clone(), None, invalid.clone()),
+            (true, None, None, None, requires_password.clone()),
+            (true, None, None, Some(KEY), requires_password.clone()),
+            (true, None, right.clone(), None, Login::Authenticated),
+            (true, None, right.clone(), Some(KEY), Login::Authenticated),
+            (true, None, wrong.clone(), None, invalid.clone()),
+            (true, None, wrong.clone(), Some(KEY), invalid.clone()),
+            (false, Some(KEY), None, None, requires_key.clone()),
+            (false, Some(KEY), None, Some(KEY), Login::Authenticated),
+            (false, Some(KEY), None, Some(OTHER_KEY), other_key.clone()),
+            (false, Some(KEY), right.clone(), Some(KEY), invalid.clone()),
+            (true, Some(KEY), None, None, requires_key.clone()),
+            (true, Some(KEY), None, Some(KEY), Login::Authenticated),
+            (
+                true,
+                Some(KEY),
+                None,
+                Some(OTHER_KEY),
+                other_key_with_password.clone(),
+            ),
+            (true, Some(KEY), right.clone(), None, Login::Authenticated),
+            (
+                true,
+                Some(KEY),
+                right.clone(),
+                Some(KEY),
+                Login::Authenticated,
+            ),
+            (
+                true,
+                Some(KEY),
+                right.clone(),
+                Some(OTHER_KEY),
+                other_key_with_password.clone(),
+            ),
+            (true, Some(KEY), wrong.clone(), Some(KEY), invalid.clone()),
+        ];
+
+        for (has_password, bound_key, password, identity_key, expected) in cases {
+            let accounts = accounts(has_password, bound_key);
+
+            let login = accounts
+                .login(USERNAME, password.clone(), identity_key)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                login, expected,
+                "password {} key {:?} sent {:?} {:?}",
+                has_password, bound_key, password, identity_key
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn login_binds_the_first_key_to_claim_a_username() {
+        let accounts = accounts(false, None);
+
+        accounts.login(USERNAME, None, Some(KEY)).await.unwrap();
+
+        assert_eq!(
+            accounts
+                .storage
+                .user(USERNAME)
+                .unwrap()
+                .unwrap()
+                .identity_key,
+            Some(KEY)
+        );
+    }
+
+    #[tokio::test]
+    async fn login_never_binds_keys_to_password_accounts() {
+        let accounts = accounts(true, None);
+
+        accounts
+            .login(USERNAME, None, Some(OTHER_KEY))
+            .await
+            .unwrap();
+        accounts
+            .login(USERNAME, Some(PASSWORD.to_string()), Some(KEY))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            accounts
+                .storage
+                .user(USERNAME)
+                .unwrap()
+                .unwrap()
+                .identity_key,
+            None
+        );
+
+        // So the password keeps working from a machine with another key
+        let login = accounts
+            .login(USERNAME, Some(PASSWORD.to_string()), Some(OTHER_KEY))
+            .await
+            .unwrap();
+
+        assert_eq!(login, Login::Authenticated);
+    }
+
+    #[tokio::test]
+    async fn login_does_not_bind_keys_when_registration_is_disabled() {
+        let accounts = Accounts::new(Arc::new(MemoryStorage::new()), false, false);
+
+        let login = accounts.login(USERNAME, None, Some(KEY)).await.unwrap();
+
+        assert_eq!(login, Login::Anonymous);
+        assert!(accounts.storage.user(USERNAME).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn login_rejects_anonymous_users_when_accounts_are_required() {
+        let accounts = Accounts::new(Arc::new(MemoryStorage::new()), true, true);
+
+        let login = accounts.login(USERNAME, None, None).await.unwrap();
+
+        assert!(matches!(login, Login::Rejected(_)));
+    }
+
+    #[tokio::test]
+    async fn register_refuses_any_claimed_username() {
+        for (has_password, bound_key) in [(true, None), (false, Some(KEY)), (true, Some(KEY))] {
+            let accounts = accounts(has_password, bound_key);
+
+            assert!(
+                !accounts
+                    .register(USERNAME, "takeover".to_string())
+                    .await
+                    .unwrap()
+            );
+
+            let user = accounts.storage.user(USERNAME).unwrap().unwrap();
+
+            assert_eq!(user.password_hash.is_some(), has_password);
+            assert_eq!(user.identity_key, bound_key);
+        }
+    }
+
+    #[tokio::test]
+    async fn register_then_login_with_the_new_password() {
+        let accounts = accounts(false, None);
+
+        assert!(
+            accounts
+                .register(USERNAME, PASSWORD.to_string())
+                .await
+                .unwrap()
+        );
+
+        let login = accounts
+            .login(USERNAME, Some(PASSWORD.to_string()), None)
+            .await
+            .unwrap();
+
+        assert_eq!(login, Login::Authenticated);
+        assert_eq!(
+            accounts.login(USERNAME, None, None).await.unwrap(),
+            rejected("alice requires a password")
+        );
+    }
+}
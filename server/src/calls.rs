@@ -0,0 +1,622 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use shared::{
+    CallRecord, Capabilities,
+    media::{MEDIA_KEY_LEN, SID_LEN},
+};
+use tokio::time::Instant;
+
+use crate::storage::unix_time;
+
+pub type Sid = [u8; SID_LEN];
+pub type CallId = u64;
+
+// Rooms have no participant cap, but a call history reply has to fit in one
+// message: 20 records of 32 full length names stay well under the limit
+const MAX_RECORDED_PEERS: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CallState {
+    // Nobody has a SID until the callee answers
+    Ringing {
+        caller: String,
+        callee: String,
+        expires_at: Instant,
+    },
+    Active,
+}
+
+#[derive(Debug)]
+pub struct Invite {
+    pub inviter: String,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+struct Leg {
+    joined_at: u64,
+    peers: BTreeSet<String>,
+}
+
+#[derive(Debug)]
+pub struct Call {
+    usernames_to_sids: HashMap<String, Sid>,
+    sids_to_media_keys: HashMap<Sid, [u8; MEDIA_KEY_LEN]>,
+    joined: HashSet<String>,
+    invited: HashMap<String, Invite>,
+    capabilities: Capabilities,
+    room: Option<String>,
+    legs: HashMap<String, Leg>,
+    state: CallState,
+}
+
+impl Call {
+    fn new(capabilities: Capabilities, room: Option<String>, state: CallState) -> Call {
+        Call {
+            usernames_to_sids: HashMap::new(),
+            sids_to_media_keys: HashMap::new(),
+            joined: HashSet::new(),
+            invited: HashMap::new(),
+            capabilities,
+            room,
+            legs: HashMap::new(),
+            state,
+        }
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.usernames_to_sids.contains_key(username)
+    }
+
+    pub fn participants(&self) -> impl Iterator<Item = &String> {
+        self.usernames_to_sids.keys()
+    }
+
+    pub fn participant_count(&self) -> usize {
+        self.usernames_to_sids.len()
+    }
+
+    pub fn room(&self) -> Option<&String> {
+        self.room.as_ref()
+    }
+
+    fn add_participant(&mut self, username: String) {
+        let sid = rand::random();
+
+        for leg in self.legs.values_mut() {
+            leg.peers.insert(username.clone());
+        }
+
+        self.legs.insert(
+            username.clone(),
+            Leg {
+                joined_at: unix_time(),
+                peers: self.usernames_to_sids.keys().cloned().collect(),
+            },
+        );
+
+        self.usernames_to_sids.insert(username, sid);
+        self.sids_to_media_keys.insert(sid, rand::random());
+    }
+
+    // Returns the participant's entry for their call history
+    fn remove_participant(&mut self, username: &str) -> Option<CallRecord> {
+        if let Some(sid) = self.usernames_to_sids.remove(username) {
+            self.sids_to_media_keys.remove(&sid);
+        }
+
+        self.joined.remove(username);
+
+        let leg = self.legs.remove(username)?;
+
+        Some(CallRecord {
+            peers: leg.peers.into_iter().take(MAX_RECORDED_PEERS).collect(),
+            room: self.room.clone(),
+            started_at: leg.joined_at,
+            ended_at: unix_time(),
+        })
+    }
+}
+
+pub struct MediaRoute {
+    pub media_key: [u8; MEDIA_KEY_LEN],
+    pub targets: Vec<Sid>,
+}
+
+// Read by the UDP loop for every datagram and written only when someone joins
+// or leaves a call, so media never waits on the signaling locks.
+#[derive(Clone, Default)]
+pub struct MediaRoutes {
+    sids_to_routes: Arc<RwLock<HashMap<Sid, Arc<MediaRoute>>>>,
+}
+
+impl MediaRoutes {
+    pub fn get(&self, sid: &Sid) -> Option<Arc<MediaRoute>> {
+        self.sids_to_routes
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(sid)
+            .cloned()
+    }
+
+    fn update(&self, call: &Call, removed_sid: Option<Sid>) {
+        let mut sids_to_routes = self
+            .sids_to_routes
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if let Some(removed_sid) = removed_sid {
+            sids_to_routes.remove(&removed_sid);
+        }
+
+        for (sid, media_key) in &call.sids_to_media_keys {
+            let targets = call
+                .sids_to_media_keys
+                .keys()
+                .filter(|other| *other != sid)
+                .copied()
+                .collect();
+
+            sids_to_routes.insert(
+                *sid,
+                Arc::new(MediaRoute {
+                    media_key: *media_key,
+                    targets,
+                }),
+            );
+        }
+    }
+
+    fn remove(&self, sids: impl IntoIterator<Item = Sid>) {
+        let mut sids_to_routes = self
+            .sids_to_routes
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+
+        for sid in sids {
+            sids_to_routes.remove(&sid);
+        }
+    }
+}
+
+// Every call, ringing or active, indexed by participant, room and pending
+// invitee so no lookup has to scan all calls.
+pub struct CallRegistry {
+    calls: HashMap<CallId, Call>,
+    next_call_id: CallId,
+    usernames_to_call_ids: HashMap<String, CallId>,
+    rooms_to_call_ids: HashMap<String, CallId>,
+    // Callers, callees and invitees with something ringing
+    pending_usernames_to_call_ids: HashMap<String, CallId>,
+    media_routes: MediaRoutes,
+}
+
+impl CallRegistry {
+    pub fn new(media_routes: MediaRoutes) -> CallRegistry {
+        CallRegistry {
+            calls: HashMap::new(),
+            next_call_id: 0,
+            usernames_to_call_ids: HashMap::new(),
+            rooms_to_call_ids: HashMap::new(),
+            pending_usernames_to_call_ids: HashMap::new(),
+            media_routes,
+        }
+    }
+
+    pub fn get(&self, call_id: CallId) -> Option<&Call> {
+        self.calls.get(&call_id)
+    }
+
+    pub fn call_id_of(&self, username: &str) -> Option<CallId> {
+        self.usernames_to_call_ids.get(username).copied()
+    }
+
+    pub fn call_of(&self, username: &str) -> Option<&Call> {
+        self.get(self.call_id_of(username)?)
+    }
+
+    pub fn in_call(&self, username: &str) -> bool {
+        self.usernames_to_call_ids.contains_key(username)
+    }
+
+    // Whether the user is calling, being called or invited
+    pub fn has_pending(&self, username: &str) -> bool {
+        self.pending_usernames_to_call_ids.contains_key(username)
+    }
+
+    pub fn ringing_between(&self, caller: &str, callee: &str) -> Option<CallId> {
+        let call_id = *self.pending_usernames_to_call_ids.get(callee)?;
+
+        match &self.calls.get(&call_id)?.state {
+            CallState::Ringing {
+                caller: ringing_caller,
+                callee: ringing_callee,
+                ..
+            } if ringing_caller == caller && ringing_callee == callee => Some(call_id),
+            _ => None,
+        }
+    }
+
+    // The call the invitee was invited into by the inviter, as long as the
+    // inviter is still in it
+    pub fn invite_from(&self, invitee: &str, inviter: &str) -> Option<CallId> {
+        let call_id = *self.pending_usernames_to_call_ids.get(invitee)?;
+        let call = self.calls.get(&call_id)?;
+
+        (call.contains(inviter)
+            && call
+                .invited
+                .get(invitee)
+                .is_some_and(|invite| invite.inviter == inviter))
+        .then_some(call_id)
+    }
+
+    // The other side of whatever is ringing for the user
+    pub fn pending_with(&self, username: &str) -> Option<String> {
+        let call = self
+            .calls
+            .get(self.pending_usernames_to_call_ids.get(username)?)?;
+
+        match &call.state {
+            CallState::Ringing { caller, .. } if caller != username => Some(caller.clone()),
+            CallState::Ringing { callee, .. } => Some(callee.clone()),
+            CallState::Active => Some(call.invited.get(username)?.inviter.clone()),
+        }
+    }
+
+    pub fn room_call_id(&self, room: &str) -> Option<CallId> {
+        self.rooms_to_call_ids.get(room).copied()
+    }
+
+    pub fn rooms(&self) -> impl Iterator<Item = &Call> {
+        self.rooms_to_call_ids
+            .values()
+            .filter_map(|call_id| self.calls.get(call_id))
+    }
+
+    fn insert(&mut self, call: Call) -> CallId {
+        let call_id = self.next_call_id;
+        self.next_call_id += 1;
+
+        self.calls.insert(call_id, call);
+
+        call_id
+    }
+
+    pub fn ring(
+        &mut self,
+        caller: &str,
+        callee: &str,
+        capabilities: Capabilities,
+        ring_timeout: Duration,
+    ) -> CallId {
+        let call_id = self.insert(Call::new(
+            capabilities,
+            None,
+            CallState::Ringing {
+                caller: caller.to_string(),
+                callee: callee.to_string(),
+                expires_at: Instant::now() + ring_timeout,
+            },
+        ));
+
+        self.pending_usernames_to_call_ids
+            .insert(caller.to_string(), call_id);
+        self.pending_usernames_to_call_ids
+            .insert(callee.to_string(), call_id);
+
+        call_id
+    }
+
+    pub fn open_room(&mut self, room: &str, capabilities: Capabilities) -> CallId {
+        if let Some(call_id) = self.room_call_id(room) {
+            return call_id;
+        }
+
+        let call_id = self.insert(Call::new(
+            capabilities,
+            Some(room.to_string()),
+            CallState::Active,
+        ));
+
+        self.rooms_to_call_ids.insert(room.to_string(), call_id);
+
+        call_id
+    }
+
+    pub fn invite(
+        &mut self,
+        call_id: CallId,
+        invitee: &str,
+        inviter: &str,
+        ring_timeout: Duration,
+    ) {
+        let Some(call) = self.calls.get_mut(&call_id) else {
+            return;
+        };
+
+        call.invited.insert(
+            invitee.to_string(),
+            Invite {
+                inviter: inviter.to_string(),
+                expires_at: Instant::now() + ring_timeout,
+            },
+        );
+
+        self.pending_usernames_to_call_ids
+            .insert(invitee.to_string(), call_id);
+    }
+
+    pub fn remove_invite(&mut self, invitee: &str) -> Option<Invite> {
+        let call_id = *self.pending_usernames_to_call_ids.get(invitee)?;
+        let invite = self.calls.get_mut(&call_id)?.invited.remove(invitee)?;
+
+        self.pending_usernames_to_call_ids.remove(invitee);
+
+        Some(invite)
+    }
+
+    pub fn answer(&mut self, call_id: CallId) {
+        let Some(call) = self.calls.get_mut(&call_id) else {
+            return;
+        };
+
+        if let CallState::Ringing { caller, callee, .. } =
+            std::mem::replace(&mut call.state, CallState::Active)
+        {
+            for username in [caller, callee] {
+                self.pending_usernames_to_call_ids.remove(&username);
+                self.usernames_to_call_ids.insert(username.clone(), call_id);
+                call.add_participant(username);
+            }
+
+            self.media_routes.update(call, None);
+        }
+    }
+
+    // Returns false when the user has no frame encoding in common with the call
+    pub fn join(&mut self, call_id: CallId, username: &str, capabilities: &Capabilities) -> bool {
+        let Some(call) = self.calls.get_mut(&call_id) else {
+            return false;
+        };
+
+        let Some(capabilities) = call.capabilities.negotiate(capabilities) else {
+            return false;
+        };
+
+        call.capabilities = capabilities;
+        call.add_participant(username.to_string());
+
+        self.usernames_to_call_ids
+            .insert(username.to_string(), call_id);
+        self.media_routes.update(call, None);
+
+        true
+    }
+
+    // Hands out the user's SID and media key and marks them as streaming.
+    // Also returns everyone who was already streaming.
+    pub fn enter(
+        &mut self,
+        username: &str,
+        call_member: &str,
+    ) -> Option<(Sid, [u8; MEDIA_KEY_LEN], Capabilities, Vec<String>)> {
+        let call_id = self.call_id_of(username)?;
+        let call = self.calls.get_mut(&call_id)?;
+
+        if !call.contains(call_member) {
+            return None;
+        }
+
+        let sid = *call.usernames_to_sids.get(username)?;
+        let media_key = *call.sids_to_media_keys.get(&sid)?;
+
+        let participants = call.joined.iter().cloned().collect();
+        call.joined.insert(username.to_string());
+
+        Some((sid, media_key, call.capabilities, participants))
+    }
+
+    pub fn remove_participant(&mut self, username: &str) -> Option<(CallId, CallRecord)> {
+        let call_id = self.usernames_to_call_ids.remove(username)?;
+        let call = self.calls.get_mut(&call_id)?;
+
+        let sid = call.usernames_to_sids.get(username).copied();
+        let record = call.remove_participant(username)?;
+
+        self.media_routes.update(call, sid);
+
+        Some((call_id, record))
+    }
+
+    // Takes the call out of the registry, returning a history entry for each
+    // participant
+    pub fn end(&mut self, call_id: CallId) -> Vec<(String, CallRecord)> {
+        let Some(mut call) = self.calls.remove(&call_id) else {
+            return Vec::new();
+        };
+
+        if let CallState::Ringing { caller, callee, .. } = &call.state {
+            self.pending_usernames_to_call_ids.remove(caller);
+            self.pending_usernames_to_call_ids.remove(callee);
+        }
+
+        for invitee in call.invited.keys() {
+            self.pending_usernames_to_call_ids.remove(invitee);
+        }
+
+        if let Some(room) = &call.room {
+            self.rooms_to_call_ids.remove(room);
+        }
+
+        self.media_routes
+            .remove(call.sids_to_media_keys.keys().copied());
+
+        let usernames: Vec<String> = call.usernames_to_sids.keys().cloned().collect();
+
+        usernames
+            .into_iter()
+            .filter_map(|username| {
+                self.usernames_to_call_ids.remove(&username);
+
+                let record = call.remove_participant(&username)?;
+                Some((username, record))
+            })
+            .collect()
+    }
+
+    // Drops calls and invites that rang for too long, returning each caller
+    // and callee
+    pub fn expire(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut timed_out = Vec::new();
+        let mut expired_call_ids = Vec::new();
+
+        for (call_id, call) in self.calls.iter_mut() {
+            if let CallState::Ringing {
+                caller,
+                callee,
+                expires_at,
+            } = &call.state
+                && *expires_at <= now
+            {
+                timed_out.push((caller.clone(), callee.clone()));
+                expired_call_ids.push(*call_id);
+            }
+
+            call.invited.retain(|invitee, invite| {
+                if invite.expires_at > now {
+                    return true;
+                }
+
+                timed_out.push((invite.inviter.clone(), invitee.clone()));
+                self.pending_usernames_to_call_ids.remove(invitee);
+                false
+            });
+        }
+
+        for call_id in expired_call_ids {
+            self.end(call_id);
+        }
+
+        timed_out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::FRAME_ENCODING_PACKED_ASCII;
+
+    use super::*;
+
+    const CAPABILITIES: Capabilities = Capabilities {
+        frame_encodings: FRAME_ENCODING_PACKED_ASCII,
+        color: false,
+        max_width: 80,
+        max_height: 24,
+    };
+    const RING_TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn answering_moves_both_sides_from_pending_into_the_call() {
+        let media_routes = MediaRoutes::default();
+        let mut calls = CallRegistry::new(media_routes.clone());
+
+        let call_id = calls.ring("alice", "bob", CAPABILITIES, RING_TIMEOUT);
+
+        assert_eq!(calls.ringing_between("alice", "bob"), Some(call_id));
+        assert_eq!(calls.ringing_between("bob", "alice"), None);
+        assert_eq!(calls.pending_with("bob"), Some("alice".to_string()));
+        assert!(!calls.in_call("alice"));
+
+        calls.answer(call_id);
+
+        assert!(!calls.has_pending("alice") && !calls.has_pending("bob"));
+        assert!(calls.in_call("alice") && calls.in_call("bob"));
+
+        // Each side's media goes to the other
+        let (alice_sid, ..) = calls.enter("alice", "bob").unwrap();
+        let (bob_sid, ..) = calls.enter("bob", "alice").unwrap();
+
+        assert_eq!(media_routes.get(&alice_sid).unwrap().targets, vec![bob_sid]);
+        assert_eq!(media_routes.get(&bob_sid).unwrap().targets, vec![alice_sid]);
+
+        let records = calls.end(call_id);
+
+        assert_eq!(records.len(), 2);
+        assert!(!calls.in_call("alice") && !calls.in_call("bob"));
+        assert!(media_routes.get(&alice_sid).is_none());
+    }
+
+    #[test]
+    fn invites_last_while_the_inviter_is_in_the_call() {
+        let mut calls = CallRegistry::new(MediaRoutes::default());
+
+        let call_id = calls.ring("alice", "bob", CAPABILITIES, RING_TIMEOUT);
+        calls.answer(call_id);
+        calls.invite(call_id, "carol", "alice", RING_TIMEOUT);
+
+        assert_eq!(calls.invite_from("carol", "alice"), Some(call_id));
+        assert_eq!(calls.invite_from("carol", "bob"), None);
+        assert_eq!(calls.pending_with("carol"), Some("alice".to_string()));
+
+        calls.remove_participant("alice");
+
+        assert_eq!(calls.invite_from("carol", "alice"), None);
+    }
+
+    #[test]
+    fn expire_drops_calls_and_invites_that_rang_too_long() {
+        let mut calls = CallRegistry::new(MediaRoutes::default());
+
+        let call_id = calls.ring("alice", "bob", CAPABILITIES, RING_TIMEOUT);
+        calls.answer(call_id);
+        calls.invite(call_id, "carol", "alice", RING_TIMEOUT);
+        calls.ring("dave", "erin", CAPABILITIES, RING_TIMEOUT);
+
+        assert!(calls.expire(Instant::now()).is_empty());
+
+        let mut timed_out = calls.expire(Instant::now() + RING_TIMEOUT);
+        timed_out.sort();
+
+        assert_eq!(
+            timed_out,
+            vec![
+                ("alice".to_string(), "carol".to_string()),
+                ("dave".to_string(), "erin".to_string())
+            ]
+        );
+        assert!(!calls.has_pending("carol") && !calls.has_pending("erin"));
+        assert!(calls.in_call("alice"));
+    }
+
+    #[test]
+    fn rooms_are_shared_by_name_and_records_cap_their_peers() {
+        let mut calls = CallRegistry::new(MediaRoutes::default());
+
+        let call_id = calls.open_room("lobby", CAPABILITIES);
+
+        assert_eq!(calls.open_room("lobby", CAPABILITIES), call_id);
+
+        let usernames: Vec<String> = (0..MAX_RECORDED_PEERS + 8)
+            .map(|i| format!("user{}", i))
+            .collect();
+
+        for username in &usernames {
+            assert!(calls.join(call_id, username, &CAPABILITIES));
+        }
+
+        let (_, record) = calls.remove_participant(&usernames[0]).unwrap();
+
+        assert_eq!(record.room, Some("lobby".to_string()));
+        assert_eq!(record.peers.len(), MAX_RECORDED_PEERS);
+        assert!(!record.peers.contains(&usernames[0]));
+        assert_eq!(
+            calls.get(call_id).unwrap().participant_count(),
+            usernames.len() - 1
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::{env, error::Error, fs, time::Duration};
+
+use clap::Parser;
+use serde::Deserialize;
+use shared::{DEFAULT_HEARTBEAT_TIMEOUT, TCP_PORT, UDP_PORT};
+
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0";
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_RING_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Flags take precedence over environment variables, which take precedence over
+// the config file.
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(short, long, env = "SERVER_CONFIG")]
+    config: Option<String>,
+
+    #[arg(long, env = "TCP_BIND_ADDR")]
+    tcp_bind_addr: Option<String>,
+
+    #[arg(long, env = "UDP_BIND_ADDR")]
+    udp_bind_addr: Option<String>,
+
+    #[arg(long, env = "TCP_PORT")]
+    tcp_port: Option<u16>,
+
+    #[arg(long, env = "UDP_PORT")]
+    udp_port: Option<u16>,
+
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+
+    #[arg(long, env = "HEARTBEAT_TIMEOUT_SECS")]
+    heartbeat_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    #[arg(long, env = "RING_TIMEOUT_SECS")]
+    ring_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "TLS_CERT_PATH")]
+    tls_cert_path: Option<String>,
+
+    #[arg(long, env = "TLS_KEY_PATH")]
+    tls_key_path: Option<String>,
+
+    #[arg(long, env = "DATABASE_PATH")]
+    database_path: Option<String>,
+
+    #[arg(long, env = "REQUIRE_ACCOUNTS")]
+    require_accounts: Option<bool>,
+
+    #[arg(long, env = "ALLOW_REGISTRATION")]
+    allow_registration: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    tcp_bind_addr: Option<String>,
+    udp_bind_addr: Option<String>,
+    tcp_port: Option<u16>,
+    udp_port: Option<u16>,
+    log_level: Option<String>,
+    heartbeat_timeout_secs: Option<u64>,
+    max_connections: Option<usize>,
+    ring_timeout_secs: Option<u64>,
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    database_path: Option<String>,
+    require_accounts: Option<bool>,
+    allow_registration: Option<bool>,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub tcp_addr: String,
+    pub udp_addr: String,
+    pub log_level: String,
+    pub heartbeat_timeout: Duration,
+    pub max_connections: usize,
+    pub ring_timeout: Duration,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub database_path: Option<String>,
+    pub require_accounts: bool,
+    pub allow_registration: bool,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Box<dyn Error + Send + Sync>> {
+        let args = Args::parse();
+
+        let file = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+
+                toml::from_str(&contents)
+                    .map_err(|e| format!("Failed to parse config {}: {}", path, e))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let tcp_bind_addr = args
+            .tcp_bind_addr
+            .or(file.tcp_bind_addr)
+            .unwrap_or_else(|| DEFAULT_BIND_ADDR.to_string());
+        let udp_bind_addr = args
+            .udp_bind_addr
+            .or(file.udp_bind_addr)
+            .unwrap_or_else(|| DEFAULT_BIND_ADDR.to_string());
+
+        let tcp_port = args.tcp_port.or(file.tcp_port).unwrap_or(TCP_PORT);
+        let udp_port = args.udp_port.or(file.udp_port).unwrap_or(UDP_PORT);
+
+        let heartbeat_timeout = args
+            .heartbeat_timeout_secs
+            .or(file.heartbeat_timeout_secs)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT);
+
+        let max_connections = args
+            .max_connections
+            .or(file.max_connections)
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+
+        if max_connections == 0 {
+            return Err("max_connections must be at least 1".into());
+        }
+
+        let ring_timeout = args
+            .ring_timeout_secs
+            .or(file.ring_timeout_secs)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RING_TIMEOUT);
+
+        if ring_timeout.is_zero() {
+            return Err("ring_timeout_secs must be at least 1".into());
+        }
+
+        let tls_cert_path = args.tls_cert_path.or(file.tls_cert_path);
+        let tls_key_path = args.tls_key_path.or(file.tls_key_path);
+
+        if tls_cert_path.is_some() != tls_key_path.is_some() {
+            return Err("tls_cert_path and tls_key_path must be set together".into());
+        }
+
+        Ok(Config {
+            tcp_addr: bind_addr(&tcp_bind_addr, tcp_port),
+            udp_addr: bind_addr(&udp_bind_addr, udp_port),
+            // RUST_LOG only fills in when no level was configured
+            log_level: args
+                .log_level
+                .or(file.log_level)
+                .or_else(|| env::var("RUST_LOG").ok())
+                .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string()),
+            heartbeat_timeout,
+            max_connections,
+            ring_timeout,
+            tls_cert_path,
+            tls_key_path,
+            database_path: args.database_path.or(file.database_path),
+            require_accounts: args
+                .require_accounts
+                .or(file.require_accounts)
+                .unwrap_or(false),
+            allow_registration: args
+                .allow_registration
+                .or(file.allow_registration)
+                .unwrap_or(true),
+        })
+    }
+}
+
+fn bind_addr(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
@@ -1,20 +1,25 @@
-use shared::{TCP_PORT, UDP_PORT};
+use config::Config;
 use std::error::Error;
 use wes_sfu::WeSFU;
 
+mod accounts;
+mod calls;
+mod config;
+mod outbound;
+mod settings;
+mod state;
+mod storage;
 mod wes_sfu;
 
-const TCP_BIND_ADDR: &str = "0.0.0.0";
-const UDP_BIND_ADDR: &str = "fly-global-services";
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let config = Config::load()?;
 
-    let tcp_addr = format!("{}:{}", TCP_BIND_ADDR, TCP_PORT);
-    let udp_addr = format!("{}:{}", UDP_BIND_ADDR, UDP_PORT);
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
 
-    let server = WeSFU::new(tcp_addr, udp_addr).await?;
+    let server = WeSFU::new(config).await?;
 
     server.run().await?;
 
@@ -0,0 +1,223 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+use shared::Command;
+use tokio::sync::Notify;
+
+// Presence and roster updates coalesce to at most a couple per user, so only
+// a client that stops reading for a long time gets here
+const OUTBOUND_QUEUE_LEN: usize = 4096;
+
+// What the state task queues for one client. Sending never blocks, so a slow
+// client can't hold up the state task or anyone else.
+#[derive(Clone)]
+pub struct OutboundSender {
+    shared: Arc<Shared>,
+}
+
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    commands: VecDeque<Command>,
+    // Why the queue was closed, once it overflows
+    closed: Option<String>,
+}
+
+pub fn channel() -> (OutboundSender, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue::default()),
+        notify: Notify::new(),
+    });
+
+    (
+        OutboundSender {
+            shared: shared.clone(),
+        },
+        OutboundReceiver { shared },
+    )
+}
+
+impl OutboundSender {
+    pub fn send(&self, command: Command) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+
+        // The connection is already being torn down, nothing will read these
+        if queue.closed.is_some() {
+            return Ok(());
+        }
+
+        match &command {
+            // Only the latest presence matters
+            Command::PresenceChanged { username, .. } => {
+                queue
+                    .commands
+                    .retain(|queued| !is_presence_of(queued, username));
+            }
+            Command::RemoveUserFromClient(username) => {
+                queue
+                    .commands
+                    .retain(|queued| !is_presence_of(queued, username));
+
+                // The client never heard about them, so it doesn't need to
+                // hear that they left either
+                let len = queue.commands.len();
+
+                queue.commands.retain(|queued| !is_added(queued, username));
+
+                if queue.commands.len() < len {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+
+        // Everything else is call control or a reply the client is waiting
+        // on, so dropping the client beats dropping the message
+        if queue.commands.len() >= OUTBOUND_QUEUE_LEN {
+            let reason = format!(
+                "Outbound queue overflowed with {} messages, disconnecting",
+                queue.commands.len()
+            );
+
+            queue.commands.clear();
+            queue.closed = Some(reason.clone());
+
+            self.shared.notify.notify_one();
+
+            return Err(reason.into());
+        }
+
+        queue.commands.push_back(command);
+
+        self.shared.notify.notify_one();
+
+        Ok(())
+    }
+}
+
+impl OutboundReceiver {
+    pub async fn recv(&mut self) -> Result<Command, Box<dyn Error + Send + Sync>> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+
+                if let Some(reason) = &queue.closed {
+                    return Err(reason.clone().into());
+                }
+
+                if let Some(command) = queue.commands.pop_front() {
+                    return Ok(command);
+                }
+            }
+
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+fn is_presence_of(command: &Command, username: &str) -> bool {
+    matches!(command, Command::PresenceChanged { username: other, .. } if other == username)
+}
+
+fn is_added(command: &Command, username: &str) -> bool {
+    matches!(command, Command::AddUserToClient(other) if other == username)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shared::Presence;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn presence(username: &str, presence: Presence) -> Command {
+        Command::PresenceChanged {
+            username: username.to_string(),
+            presence,
+        }
+    }
+
+    // Everything queued so far
+    async fn received(receiver: &mut OutboundReceiver) -> Vec<Command> {
+        let mut commands = Vec::new();
+
+        while let Ok(Ok(command)) = timeout(Duration::ZERO, receiver.recv()).await {
+            commands.push(command);
+        }
+
+        commands
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_presence_is_kept() {
+        let (sender, mut receiver) = channel();
+
+        sender.send(presence("alice", Presence::Away)).unwrap();
+        sender.send(presence("bob", Presence::Away)).unwrap();
+        sender.send(Command::EndCall).unwrap();
+        sender.send(presence("alice", Presence::InCall)).unwrap();
+
+        assert_eq!(
+            received(&mut receiver).await,
+            vec![
+                presence("bob", Presence::Away),
+                Command::EndCall,
+                presence("alice", Presence::InCall)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn leaving_cancels_a_queued_arrival() {
+        let (sender, mut receiver) = channel();
+
+        sender
+            .send(Command::AddUserToClient("alice".to_string()))
+            .unwrap();
+        sender.send(presence("alice", Presence::Away)).unwrap();
+        sender
+            .send(Command::RemoveUserFromClient("alice".to_string()))
+            .unwrap();
+
+        assert!(received(&mut receiver).await.is_empty());
+
+        // Someone the client already knows about still gets removed
+        sender
+            .send(Command::RemoveUserFromClient("bob".to_string()))
+            .unwrap();
+
+        assert_eq!(
+            received(&mut receiver).await,
+            vec![Command::RemoveUserFromClient("bob".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn overflowing_closes_the_queue() {
+        let (sender, mut receiver) = channel();
+
+        for _ in 0..OUTBOUND_QUEUE_LEN {
+            sender.send(Command::Ping).unwrap();
+        }
+
+        assert!(sender.send(Command::Ping).is_err());
+        assert!(receiver.recv().await.is_err());
+
+        // The connection is going away, later sends are quietly dropped
+        assert!(sender.send(Command::Ping).is_ok());
+        assert!(receiver.recv().await.is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::{collections::HashMap, error::Error, sync::Arc};
+
+use crate::storage::Storage;
+
+pub const ALLOW_CALLS_FROM_SETTING: &str = "allow_calls_from";
+pub const CALL_WAITING_SETTING: &str = "call_waiting";
+
+// Every setting a user can change, with its default first
+pub const SETTINGS: &[(&str, &[&str])] = &[
+    (ALLOW_CALLS_FROM_SETTING, &["everyone", "contacts"]),
+    (CALL_WAITING_SETTING, &["off", "on"]),
+];
+
+// Every known setting with the user's value or its default
+pub fn settings(
+    username: &str,
+    storage: &Arc<dyn Storage>,
+) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync>> {
+    let mut stored: HashMap<String, String> = storage.settings(username)?.into_iter().collect();
+
+    Ok(SETTINGS
+        .iter()
+        .map(|(key, values)| {
+            let value = stored.remove(*key).unwrap_or_else(|| values[0].to_string());
+
+            (key.to_string(), value)
+        })
+        .collect())
+}
+
+// The user's value for a known setting or its default
+pub fn setting(
+    username: &str,
+    key: &str,
+    storage: &Arc<dyn Storage>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let default = SETTINGS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, values)| values[0])
+        .ok_or_else(|| format!("Unknown setting {}", key))?;
+
+    Ok(storage
+        .setting(username, key)?
+        .unwrap_or_else(|| default.to_string()))
+}
+
+// What the callee's settings say about a call from the caller. Connection
+// tasks look this up so the state task never waits on storage.
+#[derive(Clone, Copy)]
+pub struct CallPolicy {
+    pub accepts_caller: bool,
+    pub call_waiting: bool,
+}
+
+pub fn call_policy(
+    storage: &Arc<dyn Storage>,
+    callee: &str,
+    caller: &str,
+) -> Result<CallPolicy, Box<dyn Error + Send + Sync>> {
+    Ok(CallPolicy {
+        accepts_caller: accepts_calls_from(storage, callee, caller)?,
+        call_waiting: setting(callee, CALL_WAITING_SETTING, storage)? == "on",
+    })
+}
+
+fn accepts_calls_from(
+    storage: &Arc<dyn Storage>,
+    callee: &str,
+    caller: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    if setting(callee, ALLOW_CALLS_FROM_SETTING, storage)? != "contacts" {
+        return Ok(true);
+    }
+
+    Ok(storage
+        .contacts(callee)?
+        .iter()
+        .any(|contact| contact == caller))
+}
@@ -0,0 +1,1361 @@
+use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
+
+use log::{error, info};
+use shared::{
+    CallRecord, Capabilities, Command, ErrorCode, MAX_ROOM_NAME_LEN, Presence,
+    e2e::SEALED_SENDER_KEY_LEN, is_valid_room_name,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{Instant, interval_at},
+};
+
+use crate::{
+    calls::CallRegistry, outbound::OutboundSender, settings::CallPolicy, storage::Storage,
+};
+
+const EVENT_QUEUE_LEN: usize = 1024;
+const RING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// With MAX_USERNAME_LEN names this stays well under the message size limit
+const PRESENCE_SNAPSHOT_CHUNK_LEN: usize = 256;
+// Keeps RoomList well under the message size limit
+const MAX_LISTED_ROOMS: usize = 256;
+
+type Reply = Result<(), (ErrorCode, String)>;
+
+enum Event {
+    // Replies false when the username is already taken
+    Join {
+        username: String,
+        capabilities: Capabilities,
+        verified: bool,
+        outbound: OutboundSender,
+        reply: oneshot::Sender<bool>,
+    },
+    Leave {
+        username: String,
+    },
+    // Any signaling command from a logged in user, replies go out on the
+    // user's outbound channel
+    Command {
+        username: String,
+        command: Command,
+    },
+    Online {
+        usernames: Vec<String>,
+        reply: oneshot::Sender<Vec<(String, bool)>>,
+    },
+    RequestCall {
+        caller: String,
+        callee: String,
+        policy: CallPolicy,
+    },
+    InviteToCall {
+        inviter: String,
+        invitee: String,
+        policy: CallPolicy,
+    },
+}
+
+// What connection tasks hold to talk to the state task
+#[derive(Clone)]
+pub struct StateHandle {
+    events: mpsc::Sender<Event>,
+}
+
+impl StateHandle {
+    pub async fn join(
+        &self,
+        username: &str,
+        capabilities: Capabilities,
+        verified: bool,
+        outbound: OutboundSender,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let (reply, joined) = oneshot::channel();
+
+        self.send(Event::Join {
+            username: username.to_string(),
+            capabilities,
+            verified,
+            outbound,
+            reply,
+        })
+        .await?;
+
+        Ok(joined.await?)
+    }
+
+    pub async fn leave(&self, username: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(Event::Leave {
+            username: username.to_string(),
+        })
+        .await
+    }
+
+    pub async fn command(
+        &self,
+        username: &str,
+        command: Command,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(Event::Command {
+            username: username.to_string(),
+            command,
+        })
+        .await
+    }
+
+    pub async fn request_call(
+        &self,
+        caller: &str,
+        callee: String,
+        policy: CallPolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(Event::RequestCall {
+            caller: caller.to_string(),
+            callee,
+            policy,
+        })
+        .await
+    }
+
+    pub async fn invite_to_call(
+        &self,
+        inviter: &str,
+        invitee: String,
+        policy: CallPolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(Event::InviteToCall {
+            inviter: inviter.to_string(),
+            invitee,
+            policy,
+        })
+        .await
+    }
+
+    // Pairs each username with whether they are online
+    pub async fn online(
+        &self,
+        usernames: Vec<String>,
+    ) -> Result<Vec<(String, bool)>, Box<dyn Error + Send + Sync>> {
+        let (reply, online) = oneshot::channel();
+
+        self.send(Event::Online { usernames, reply }).await?;
+
+        Ok(online.await?)
+    }
+
+    async fn send(&self, event: Event) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.events
+            .send(event)
+            .await
+            .map_err(|_| "Server state task has stopped".into())
+    }
+}
+
+struct Session {
+    outbound: OutboundSender,
+    capabilities: Capabilities,
+    // Logged in with a password or identity key rather than anonymously
+    verified: bool,
+    // What the user picked, see presence_of for what others see
+    presence: Presence,
+}
+
+// Owns every session and call. Events are handled one at a time and nothing
+// in here awaits, so there are no locks to order and no socket can stall it.
+pub struct ServerState {
+    sessions: HashMap<String, Session>,
+    calls: CallRegistry,
+    storage: Arc<dyn Storage>,
+    ring_timeout: Duration,
+}
+
+impl ServerState {
+    pub fn new(calls: CallRegistry, storage: Arc<dyn Storage>, ring_timeout: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            calls,
+            storage,
+            ring_timeout,
+        }
+    }
+
+    pub fn spawn(self) -> StateHandle {
+        let (events_tx, events_rx) = mpsc::channel(EVENT_QUEUE_LEN);
+
+        tokio::spawn(self.run(events_rx));
+
+        StateHandle { events: events_tx }
+    }
+
+    async fn run(mut self, mut events: mpsc::Receiver<Event>) {
+        let mut sweep = interval_at(Instant::now() + RING_SWEEP_INTERVAL, RING_SWEEP_INTERVAL);
+
+        loop {
+            tokio::select! {
+
+                event = events.recv() => {
+
+                    match event {
+                        Some(event) => self.handle_event(event),
+                        None => return,
+                    }
+                }
+
+                _ = sweep.tick() => {
+
+                    self.expire_ringing(Instant::now());
+                }
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Join {
+                username,
+                capabilities,
+                verified,
+                outbound,
+                reply,
+            } => {
+                let joined = self.join(username, capabilities, verified, outbound);
+
+                let _ = reply.send(joined);
+            }
+            Event::Leave { username } => self.leave(&username),
+            Event::Command { username, command } => {
+                let result = self.handle_command(&username, command);
+
+                self.reply(&username, result);
+            }
+            Event::RequestCall {
+                caller,
+                callee,
+                policy,
+            } => {
+                let result = self.request_call(&caller, callee, policy);
+
+                self.reply(&caller, result);
+            }
+            Event::InviteToCall {
+                inviter,
+                invitee,
+                policy,
+            } => {
+                let result = self.invite_to_call(&inviter, invitee, policy);
+
+                self.reply(&inviter, result);
+            }
+            Event::Online { usernames, reply } => {
+                let online = usernames
+                    .into_iter()
+                    .map(|username| {
+                        let online = self.sessions.contains_key(&username);
+                        (username, online)
+                    })
+                    .collect();
+
+                let _ = reply.send(online);
+            }
+        }
+    }
+
+    // Errors go back to the user who sent the command
+    fn reply(&self, username: &str, result: Reply) {
+        if let Err((code, reason)) = result {
+            info!("Replying to {} with error {:?}: {}", username, code, reason);
+
+            self.send(username, Command::Error { code, reason });
+        }
+    }
+
+    fn handle_command(&mut self, current_name: &str, command: Command) -> Reply {
+        match command {
+            Command::StartCall(caller) => self.start_call(current_name, caller),
+            Command::DenyCall(caller) => self.deny_call(current_name, caller),
+            Command::CancelCall(callee) => self.cancel_call(current_name, callee),
+            Command::RequestCallStreamId(username) => {
+                if self.enter_call(current_name, &username) {
+                    Ok(())
+                } else {
+                    Err((
+                        ErrorCode::CallNotFound,
+                        format!("No call with {} exists", username),
+                    ))
+                }
+            }
+            Command::JoinRoom(room) => self.join_room(current_name, room),
+            Command::LeaveRoom => self.leave_room(current_name),
+            Command::ListRooms => {
+                let mut rooms: Vec<(String, u16)> = self
+                    .calls
+                    .rooms()
+                    .filter_map(|call| {
+                        let room = call.room()?.clone();
+                        Some((room, call.participant_count().min(u16::MAX as usize) as u16))
+                    })
+                    .collect();
+
+                // Busiest rooms first when there are too many to list
+                rooms.sort_by(|(room, count), (other_room, other_count)| {
+                    other_count.cmp(count).then_with(|| room.cmp(other_room))
+                });
+                rooms.truncate(MAX_LISTED_ROOMS);
+
+                self.send(current_name, Command::RoomList(rooms));
+
+                Ok(())
+            }
+            Command::CallPublicKey {
+                username,
+                public_key,
+            } => {
+                let relayed_command = Command::CallPublicKey {
+                    username: current_name.to_string(),
+                    public_key,
+                };
+
+                self.relay_to_participant(current_name, &username, relayed_command)
+            }
+            Command::CallSenderKey {
+                username,
+                sealed_key,
+            } => {
+                if sealed_key.len() != SEALED_SENDER_KEY_LEN {
+                    return Err((
+                        ErrorCode::InvalidCommand,
+                        "Sealed sender key has the wrong length".to_string(),
+                    ));
+                }
+
+                let relayed_command = Command::CallSenderKey {
+                    username: current_name.to_string(),
+                    sealed_key,
+                };
+
+                self.relay_to_participant(current_name, &username, relayed_command)
+            }
+            Command::SetPresence(presence) => {
+                if presence == Presence::InCall {
+                    return Err((
+                        ErrorCode::InvalidCommand,
+                        "In call is set by the server".to_string(),
+                    ));
+                }
+
+                if let Some(session) = self.sessions.get_mut(current_name) {
+                    session.presence = presence;
+                }
+
+                info!("{} set their presence to {:?}", current_name, presence);
+
+                self.broadcast_presence(current_name);
+
+                Ok(())
+            }
+            command => Err((
+                ErrorCode::InvalidCommand,
+                format!("Unexpected command {:?}", command),
+            )),
+        }
+    }
+
+    fn join(
+        &mut self,
+        username: String,
+        capabilities: Capabilities,
+        verified: bool,
+        outbound: OutboundSender,
+    ) -> bool {
+        if self.sessions.contains_key(&username) {
+            info!("Username: {} was already taken", username);
+            return false;
+        }
+
+        for user in self.sessions.keys() {
+            self.send(user, Command::AddUserToClient(username.clone()));
+        }
+
+        let presence_snapshot: Vec<(String, Presence)> = self
+            .sessions
+            .keys()
+            .filter_map(|user| Some((user.clone(), self.presence_of(user)?)))
+            .collect();
+
+        // Always at least one message so the client knows the snapshot arrived
+        let chunks: Vec<Vec<(String, Presence)>> = if presence_snapshot.is_empty() {
+            vec![Vec::new()]
+        } else {
+            presence_snapshot
+                .chunks(PRESENCE_SNAPSHOT_CHUNK_LEN)
+                .map(|chunk| chunk.to_vec())
+                .collect()
+        };
+
+        for chunk in chunks {
+            if let Err(e) = outbound.send(Command::PresenceSnapshot(chunk)) {
+                error!("Error sending presence snapshot to {}: {}", username, e);
+                break;
+            }
+        }
+
+        info!("{} has connected!", username);
+
+        self.sessions.insert(
+            username,
+            Session {
+                outbound,
+                capabilities,
+                verified,
+                presence: Presence::Available,
+            },
+        );
+
+        true
+    }
+
+    fn leave(&mut self, username: &str) {
+        if self.sessions.remove(username).is_none() {
+            return;
+        }
+
+        for user in self.sessions.keys() {
+            self.send(user, Command::RemoveUserFromClient(username.to_string()));
+        }
+
+        if self.calls.remove_invite(username).is_none()
+            && let Some(other) = self.calls.pending_with(username)
+            && let Some(call_id) = self
+                .calls
+                .ringing_between(username, &other)
+                .or_else(|| self.calls.ringing_between(&other, username))
+        {
+            info!(
+                "Call between {} and {} cancelled when {} left",
+                username, other, username
+            );
+
+            self.send(&other, Command::CancelCall(username.to_string()));
+            self.calls.end(call_id);
+        }
+
+        self.leave_calls(username);
+
+        info!("{} has disconnected!", username);
+    }
+
+    fn request_call(&mut self, current_name: &str, callee: String, policy: CallPolicy) -> Reply {
+        if callee == current_name {
+            return Err((
+                ErrorCode::InvalidCommand,
+                "You can't call yourself".to_string(),
+            ));
+        }
+
+        if !policy.accepts_caller {
+            return Err((
+                ErrorCode::CallNotAllowed,
+                format!("{} only accepts calls from their contacts", callee),
+            ));
+        }
+
+        if self.does_not_disturb(&callee) {
+            return Err((
+                ErrorCode::CallNotAllowed,
+                format!("{} does not want to be disturbed", callee),
+            ));
+        }
+
+        let callee_in_call = self.calls.in_call(&callee);
+
+        if self.calls.ringing_between(&callee, current_name).is_some() {
+            return Err((
+                ErrorCode::CallAlreadyPending,
+                format!("{} is already calling you", callee),
+            ));
+        }
+
+        if self.calls.has_pending(current_name) {
+            return Err((
+                ErrorCode::CallAlreadyPending,
+                "You already have a call ringing".to_string(),
+            ));
+        }
+
+        if self.calls.has_pending(&callee) {
+            return Err((
+                ErrorCode::CallAlreadyPending,
+                format!("{} already has a call ringing", callee),
+            ));
+        }
+
+        if self.calls.in_call(current_name) {
+            return Err((
+                ErrorCode::UserInCall,
+                "You are already in a call".to_string(),
+            ));
+        }
+
+        if callee_in_call && !policy.call_waiting {
+            info!("{} called {} but they are busy", current_name, callee);
+
+            self.send(current_name, Command::Busy(callee));
+
+            return Ok(());
+        }
+
+        let (Some(caller_session), Some(callee_session)) =
+            (self.sessions.get(current_name), self.sessions.get(&callee))
+        else {
+            return Err((ErrorCode::UserNotFound, format!("{} is not online", callee)));
+        };
+
+        let Some(capabilities) = caller_session
+            .capabilities
+            .negotiate(&callee_session.capabilities)
+        else {
+            return Err((
+                ErrorCode::IncompatibleCapabilities,
+                format!("You and {} have no frame encoding in common", callee),
+            ));
+        };
+
+        // Callees already in a call get a call waiting notice instead
+        let request = if callee_in_call {
+            Command::CallWaiting(current_name.to_string())
+        } else {
+            Command::RequestCall(current_name.to_string())
+        };
+
+        let sent = callee_session
+            .outbound
+            .send(self.peer_identity(current_name))
+            .and_then(|_| callee_session.outbound.send(request));
+
+        if sent.is_err() {
+            return Err((ErrorCode::UserNotFound, format!("{} is not online", callee)));
+        }
+
+        info!("{} is calling {}", current_name, callee);
+
+        self.calls
+            .ring(current_name, &callee, capabilities, self.ring_timeout);
+
+        if callee_in_call {
+            self.send(current_name, Command::CallWaiting(callee));
+        }
+
+        Ok(())
+    }
+
+    fn start_call(&mut self, current_name: &str, caller: String) -> Reply {
+        if self.join_call(current_name, &caller)? {
+            return Ok(());
+        }
+
+        let Some(call_id) = self.calls.ringing_between(&caller, current_name) else {
+            return Err((
+                ErrorCode::CallNotFound,
+                format!("{} is not calling you", caller),
+            ));
+        };
+
+        // Answering a waiting call hangs up the one the callee is in
+        self.leave_calls(current_name);
+
+        self.calls.answer(call_id);
+
+        for name in [current_name, &caller] {
+            self.broadcast_presence(name);
+        }
+
+        self.send(&caller, self.peer_identity(current_name));
+        self.send(&caller, Command::StartCall(current_name.to_string()));
+
+        info!("Call started between {} and {}", caller, current_name);
+
+        Ok(())
+    }
+
+    // Joins the user into the call of whoever invited them. Returns false
+    // when there was no invite so StartCall falls back to answering a call.
+    fn join_call(
+        &mut self,
+        current_name: &str,
+        inviter: &str,
+    ) -> Result<bool, (ErrorCode, String)> {
+        let Some(call_id) = self.calls.invite_from(current_name, inviter) else {
+            return Ok(false);
+        };
+
+        self.calls.remove_invite(current_name);
+
+        let capabilities = self
+            .sessions
+            .get(current_name)
+            .map(|session| session.capabilities);
+
+        match capabilities {
+            Some(capabilities) if self.calls.join(call_id, current_name, &capabilities) => {
+                self.broadcast_presence(current_name);
+
+                info!("{} joined {}'s call", current_name, inviter);
+
+                Ok(true)
+            }
+            _ => Err((
+                ErrorCode::IncompatibleCapabilities,
+                format!(
+                    "You and {}'s call have no frame encoding in common",
+                    inviter
+                ),
+            )),
+        }
+    }
+
+    fn deny_call(&mut self, current_name: &str, caller: String) -> Reply {
+        if self.calls.pending_with(current_name).as_ref() != Some(&caller) {
+            return Err((
+                ErrorCode::CallNotFound,
+                format!("{} is not calling you", caller),
+            ));
+        }
+
+        match self.calls.ringing_between(&caller, current_name) {
+            Some(call_id) => {
+                self.calls.end(call_id);
+            }
+            None => {
+                self.calls.remove_invite(current_name);
+            }
+        }
+
+        self.send(&caller, Command::DenyCall(current_name.to_string()));
+
+        info!("{} declined a call from {}", current_name, caller);
+
+        Ok(())
+    }
+
+    fn cancel_call(&mut self, current_name: &str, callee: String) -> Reply {
+        let Some(call_id) = self.calls.ringing_between(current_name, &callee) else {
+            return Err((
+                ErrorCode::CallNotFound,
+                format!("You are not calling {}", callee),
+            ));
+        };
+
+        self.calls.end(call_id);
+
+        self.send(&callee, Command::CancelCall(current_name.to_string()));
+
+        info!("{} stopped calling {}", current_name, callee);
+
+        Ok(())
+    }
+
+    fn join_room(&mut self, current_name: &str, room: String) -> Reply {
+        if !is_valid_room_name(&room) {
+            return Err((
+                ErrorCode::InvalidCommand,
+                format!(
+                    "Room names must be 1 to {} letters, digits, '-', '_' or '.'",
+                    MAX_ROOM_NAME_LEN
+                ),
+            ));
+        }
+
+        if self.calls.in_call(current_name) {
+            return Err((
+                ErrorCode::UserInCall,
+                "You are already in a call".to_string(),
+            ));
+        }
+
+        if self.calls.has_pending(current_name) {
+            return Err((
+                ErrorCode::CallAlreadyPending,
+                "You have a call ringing".to_string(),
+            ));
+        }
+
+        let Some(capabilities) = self
+            .sessions
+            .get(current_name)
+            .map(|session| session.capabilities)
+        else {
+            return Err((
+                ErrorCode::NotLoggedIn,
+                "You must say hello before joining a room".to_string(),
+            ));
+        };
+
+        let call_id = self.calls.open_room(&room, capabilities);
+
+        if !self.calls.join(call_id, current_name, &capabilities) {
+            // Don't leave behind a room that was only opened for this join
+            if self
+                .calls
+                .get(call_id)
+                .is_some_and(|call| call.participant_count() == 0)
+            {
+                self.calls.end(call_id);
+            }
+
+            return Err((
+                ErrorCode::IncompatibleCapabilities,
+                format!("You have no frame encoding in common with room {}", room),
+            ));
+        }
+
+        self.broadcast_presence(current_name);
+
+        info!("{} joined room {}", current_name, room);
+
+        self.enter_call(current_name, current_name);
+
+        Ok(())
+    }
+
+    fn leave_room(&mut self, current_name: &str) -> Reply {
+        let Some(room) = self
+            .calls
+            .call_of(current_name)
+            .and_then(|call| call.room().cloned())
+        else {
+            return Err((ErrorCode::CallNotFound, "You are not in a room".to_string()));
+        };
+
+        self.leave_calls(current_name);
+
+        info!("{} left room {}", current_name, room);
+
+        self.send(current_name, Command::EndCall);
+
+        Ok(())
+    }
+
+    fn invite_to_call(
+        &mut self,
+        current_name: &str,
+        username: String,
+        policy: CallPolicy,
+    ) -> Reply {
+        if self.calls.in_call(&username) {
+            return Err((
+                ErrorCode::UserInCall,
+                format!("{} is already in a call", username),
+            ));
+        }
+
+        if self.calls.has_pending(&username) {
+            return Err((
+                ErrorCode::CallAlreadyPending,
+                format!("{} already has a call ringing", username),
+            ));
+        }
+
+        if !policy.accepts_caller {
+            return Err((
+                ErrorCode::CallNotAllowed,
+                format!("{} only accepts calls from their contacts", username),
+            ));
+        }
+
+        if self.does_not_disturb(&username) {
+            return Err((
+                ErrorCode::CallNotAllowed,
+                format!("{} does not want to be disturbed", username),
+            ));
+        }
+
+        let Some(call_id) = self.calls.call_id_of(current_name) else {
+            return Err((ErrorCode::CallNotFound, "You are not in a call".to_string()));
+        };
+
+        match self.sessions.get(&username) {
+            Some(session)
+                if session
+                    .outbound
+                    .send(self.peer_identity(current_name))
+                    .and_then(|_| {
+                        session
+                            .outbound
+                            .send(Command::RequestCall(current_name.to_string()))
+                    })
+                    .is_ok() =>
+            {
+                self.calls
+                    .invite(call_id, &username, current_name, self.ring_timeout);
+
+                info!("{} invited {} to their call", current_name, username);
+
+                Ok(())
+            }
+            _ => Err((
+                ErrorCode::UserNotFound,
+                format!("{} is not online", username),
+            )),
+        }
+    }
+
+    fn relay_to_participant(&self, current_name: &str, username: &str, command: Command) -> Reply {
+        let in_same_call = self
+            .calls
+            .call_of(current_name)
+            .is_some_and(|call| call.contains(username));
+
+        let relayed = in_same_call
+            && current_name != username
+            && self
+                .sessions
+                .get(username)
+                .is_some_and(|session| session.outbound.send(command).is_ok());
+
+        if relayed {
+            Ok(())
+        } else {
+            Err((
+                ErrorCode::CallNotFound,
+                format!("{} is not in your call", username),
+            ))
+        }
+    }
+
+    // Hands the user its SID and media key for the call it shares with
+    // call_member, and introduces it to everyone already streaming.
+    fn enter_call(&mut self, current_name: &str, call_member: &str) -> bool {
+        let Some((sid, media_key, capabilities, participants)) =
+            self.calls.enter(current_name, call_member)
+        else {
+            return false;
+        };
+
+        self.send(
+            current_name,
+            Command::SendCallStreamId {
+                sid,
+                capabilities,
+                media_key,
+            },
+        );
+
+        for participant in participants.iter() {
+            self.send(participant, self.peer_identity(current_name));
+            self.send(
+                participant,
+                Command::ParticipantJoined(current_name.to_string()),
+            );
+        }
+
+        for participant in participants {
+            self.send(current_name, self.peer_identity(&participant));
+            self.send(current_name, Command::ParticipantJoined(participant));
+        }
+
+        true
+    }
+
+    // Takes the user out of the call they are in. Rooms close once empty,
+    // calls end once one person is left.
+    fn leave_calls(&mut self, username: &str) {
+        let Some((call_id, record)) = self.calls.remove_participant(username) else {
+            return;
+        };
+
+        self.record_call(username, &record);
+
+        let mut left = vec![username.to_string()];
+
+        let Some(call) = self.calls.get(call_id) else {
+            return;
+        };
+
+        let call_ended = match call.room() {
+            Some(_) => call.participant_count() == 0,
+            None => call.participant_count() < 2,
+        };
+
+        let notification = if call_ended {
+            info!("Call ended when {} left", username);
+            Command::EndCall
+        } else {
+            info!("{} left their call", username);
+            Command::ParticipantLeft(username.to_string())
+        };
+
+        for participant in call.participants() {
+            self.send(participant, notification.clone());
+        }
+
+        if call_ended {
+            for (participant, record) in self.calls.end(call_id) {
+                self.record_call(&participant, &record);
+                left.push(participant);
+            }
+        }
+
+        for username in left {
+            self.broadcast_presence(&username);
+        }
+    }
+
+    // Drops calls and invites nobody answered in time and tells both sides
+    fn expire_ringing(&mut self, now: Instant) {
+        for (caller, callee) in self.calls.expire(now) {
+            info!("Call from {} to {} was not answered", caller, callee);
+
+            self.send(&caller, Command::CallTimedOut(callee.clone()));
+            self.send(&callee, Command::CallTimedOut(caller));
+        }
+    }
+
+    // What everyone else sees, being in a call or room overrides what the
+    // user picked. None when the user is offline.
+    fn presence_of(&self, username: &str) -> Option<Presence> {
+        let presence = self.sessions.get(username)?.presence;
+
+        if self.calls.in_call(username) {
+            Some(Presence::InCall)
+        } else {
+            Some(presence)
+        }
+    }
+
+    fn does_not_disturb(&self, username: &str) -> bool {
+        self.sessions
+            .get(username)
+            .is_some_and(|session| session.presence == Presence::DoNotDisturb)
+    }
+
+    fn broadcast_presence(&self, username: &str) {
+        let Some(presence) = self.presence_of(username) else {
+            return;
+        };
+
+        for user in self.sessions.keys() {
+            if user != username {
+                self.send(
+                    user,
+                    Command::PresenceChanged {
+                        username: username.to_string(),
+                        presence,
+                    },
+                );
+            }
+        }
+    }
+
+    // Tells someone about to meet the user in a call how they logged in
+    fn peer_identity(&self, username: &str) -> Command {
+        Command::PeerIdentity {
+            username: username.to_string(),
+            verified: self
+                .sessions
+                .get(username)
+                .is_some_and(|session| session.verified),
+        }
+    }
+
+    fn send(&self, username: &str, command: Command) {
+        if let Some(session) = self.sessions.get(username)
+            && let Err(e) = session.outbound.send(command)
+        {
+            error!("Error sending to {}: {}", username, e);
+        }
+    }
+
+    // Anonymous names can be reused by anyone, so only accounts keep a history
+    // Written on the blocking pool so a slow database can't stall the state task
+    fn record_call(&self, username: &str, record: &CallRecord) {
+        let storage = self.storage.clone();
+        let username = username.to_string();
+        let record = record.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let result = match storage.user(&username) {
+                Ok(Some(_)) => storage.record_call(&username, &record),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                error!("Error recording call history for {}: {}", username, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::FRAME_ENCODING_PACKED_ASCII;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        calls::MediaRoutes,
+        outbound::{self, OutboundReceiver},
+        storage::MemoryStorage,
+    };
+
+    const CAPABILITIES: Capabilities = Capabilities {
+        frame_encodings: FRAME_ENCODING_PACKED_ASCII,
+        color: false,
+        max_width: 80,
+        max_height: 24,
+    };
+    const RING_TIMEOUT: Duration = Duration::from_secs(30);
+    const ALLOW: CallPolicy = CallPolicy {
+        accepts_caller: true,
+        call_waiting: false,
+    };
+
+    fn state() -> ServerState {
+        ServerState::new(
+            CallRegistry::new(MediaRoutes::default()),
+            Arc::new(MemoryStorage::new()),
+            RING_TIMEOUT,
+        )
+    }
+
+    // Joins everyone and drops what they were told on the way in
+    async fn join(
+        state: &mut ServerState,
+        usernames: &[&str],
+    ) -> HashMap<String, OutboundReceiver> {
+        let mut receivers = HashMap::new();
+
+        for username in usernames {
+            let (outbound, receiver) = outbound::channel();
+
+            assert!(state.join(username.to_string(), CAPABILITIES, false, outbound));
+
+            receivers.insert(username.to_string(), receiver);
+        }
+
+        for receiver in receivers.values_mut() {
+            received(receiver).await;
+        }
+
+        receivers
+    }
+
+    // Everything queued for the user so far
+    async fn received(receiver: &mut OutboundReceiver) -> Vec<Command> {
+        let mut commands = Vec::new();
+
+        while let Ok(Ok(command)) = timeout(Duration::ZERO, receiver.recv()).await {
+            commands.push(command);
+        }
+
+        commands
+    }
+
+    fn error_code(reply: Reply) -> Option<ErrorCode> {
+        reply.err().map(|(code, _)| code)
+    }
+
+    async fn start_call(
+        state: &mut ServerState,
+        users: &mut HashMap<String, OutboundReceiver>,
+        caller: &str,
+        callee: &str,
+    ) {
+        state
+            .request_call(caller, callee.to_string(), ALLOW)
+            .unwrap();
+        state
+            .handle_command(callee, Command::StartCall(caller.to_string()))
+            .unwrap();
+
+        for receiver in users.values_mut() {
+            received(receiver).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn join_introduces_users_and_refuses_taken_names() {
+        let mut state = state();
+
+        let (outbound, mut alice) = outbound::channel();
+        assert!(state.join("alice".to_string(), CAPABILITIES, true, outbound));
+
+        assert_eq!(
+            received(&mut alice).await,
+            vec![Command::PresenceSnapshot(Vec::new())]
+        );
+
+        let (outbound, mut bob) = outbound::channel();
+        assert!(state.join("bob".to_string(), CAPABILITIES, false, outbound));
+
+        assert_eq!(
+            received(&mut bob).await,
+            vec![Command::PresenceSnapshot(vec![(
+                "alice".to_string(),
+                Presence::Available
+            )])]
+        );
+        assert_eq!(
+            received(&mut alice).await,
+            vec![Command::AddUserToClient("bob".to_string())]
+        );
+
+        let (outbound, _) = outbound::channel();
+        assert!(!state.join("alice".to_string(), CAPABILITIES, false, outbound));
+
+        state.leave("bob");
+
+        assert_eq!(
+            received(&mut alice).await,
+            vec![Command::RemoveUserFromClient("bob".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn answered_calls_end_when_one_side_leaves() {
+        let mut state = state();
+        let mut users = join(&mut state, &["alice", "bob"]).await;
+
+        state
+            .request_call("alice", "bob".to_string(), ALLOW)
+            .unwrap();
+
+        assert_eq!(
+            received(users.get_mut("bob").unwrap()).await,
+            vec![
+                Command::PeerIdentity {
+                    username: "alice".to_string(),
+                    verified: false
+                },
+                Command::RequestCall("alice".to_string())
+            ]
+        );
+
+        state
+            .handle_command("bob", Command::StartCall("alice".to_string()))
+            .unwrap();
+
+        assert!(state.calls.in_call("alice") && state.calls.in_call("bob"));
+        assert!(
+            received(users.get_mut("alice").unwrap())
+                .await
+                .contains(&Command::StartCall("bob".to_string()))
+        );
+
+        state.leave("alice");
+
+        assert!(
+            received(users.get_mut("bob").unwrap())
+                .await
+                .contains(&Command::EndCall)
+        );
+        assert!(!state.calls.in_call("bob"));
+    }
+
+    #[tokio::test]
+    async fn request_call_follows_the_callee_policy() {
+        let mut state = state();
+        join(&mut state, &["alice", "bob"]).await;
+
+        let contacts_only = CallPolicy {
+            accepts_caller: false,
+            ..ALLOW
+        };
+
+        assert_eq!(
+            error_code(state.request_call("alice", "alice".to_string(), ALLOW)),
+            Some(ErrorCode::InvalidCommand)
+        );
+        assert_eq!(
+            error_code(state.request_call("alice", "bob".to_string(), contacts_only)),
+            Some(ErrorCode::CallNotAllowed)
+        );
+        assert_eq!(
+            error_code(state.request_call("alice", "carol".to_string(), ALLOW)),
+            Some(ErrorCode::UserNotFound)
+        );
+
+        state
+            .handle_command("bob", Command::SetPresence(Presence::DoNotDisturb))
+            .unwrap();
+
+        assert_eq!(
+            error_code(state.request_call("alice", "bob".to_string(), ALLOW)),
+            Some(ErrorCode::CallNotAllowed)
+        );
+        assert!(!state.calls.has_pending("alice"));
+    }
+
+    #[tokio::test]
+    async fn callees_in_a_call_are_busy_unless_they_have_call_waiting() {
+        let mut state = state();
+        let mut users = join(&mut state, &["alice", "bob", "carol"]).await;
+
+        start_call(&mut state, &mut users, "alice", "bob").await;
+
+        state
+            .request_call("carol", "bob".to_string(), ALLOW)
+            .unwrap();
+
+        assert_eq!(
+            received(users.get_mut("carol").unwrap()).await,
+            vec![Command::Busy("bob".to_string())]
+        );
+        assert!(!state.calls.has_pending("carol"));
+
+        let call_waiting = CallPolicy {
+            call_waiting: true,
+            ..ALLOW
+        };
+
+        state
+            .request_call("carol", "bob".to_string(), call_waiting)
+            .unwrap();
+
+        assert_eq!(
+            received(users.get_mut("carol").unwrap()).await,
+            vec![Command::CallWaiting("bob".to_string())]
+        );
+        assert_eq!(
+            received(users.get_mut("bob").unwrap()).await.last(),
+            Some(&Command::CallWaiting("carol".to_string()))
+        );
+
+        // Answering the waiting call hangs up the first one
+        state
+            .handle_command("bob", Command::StartCall("carol".to_string()))
+            .unwrap();
+
+        assert!(
+            received(users.get_mut("alice").unwrap())
+                .await
+                .contains(&Command::EndCall)
+        );
+        assert_eq!(
+            state.calls.call_id_of("bob"),
+            state.calls.call_id_of("carol")
+        );
+        assert!(!state.calls.in_call("alice"));
+    }
+
+    #[tokio::test]
+    async fn unanswered_calls_are_cancelled_or_time_out() {
+        let mut state = state();
+        let mut users = join(&mut state, &["alice", "bob", "carol"]).await;
+
+        state
+            .request_call("alice", "bob".to_string(), ALLOW)
+            .unwrap();
+        state.leave("alice");
+
+        assert_eq!(
+            received(users.get_mut("bob").unwrap()).await.last(),
+            Some(&Command::CancelCall("alice".to_string()))
+        );
+        assert!(!state.calls.has_pending("bob"));
+
+        state
+            .request_call("bob", "carol".to_string(), ALLOW)
+            .unwrap();
+        received(users.get_mut("carol").unwrap()).await;
+
+        state.expire_ringing(Instant::now() + RING_TIMEOUT);
+
+        assert_eq!(
+            received(users.get_mut("bob").unwrap()).await,
+            vec![Command::CallTimedOut("carol".to_string())]
+        );
+        assert_eq!(
+            received(users.get_mut("carol").unwrap()).await,
+            vec![Command::CallTimedOut("bob".to_string())]
+        );
+        assert!(!state.calls.has_pending("bob") && !state.calls.has_pending("carol"));
+    }
+
+    #[tokio::test]
+    async fn rooms_close_once_the_last_participant_leaves() {
+        let mut state = state();
+        let mut users = join(&mut state, &["alice", "bob"]).await;
+
+        assert_eq!(
+            error_code(state.handle_command("alice", Command::JoinRoom("no spaces".to_string()))),
+            Some(ErrorCode::InvalidCommand)
+        );
+
+        for username in ["alice", "bob"] {
+            state
+                .handle_command(username, Command::JoinRoom("lobby".to_string()))
+                .unwrap();
+        }
+
+        let bob = received(users.get_mut("bob").unwrap()).await;
+
+        assert!(
+            bob.iter()
+                .any(|command| matches!(command, Command::SendCallStreamId { .. }))
+        );
+        assert!(bob.contains(&Command::ParticipantJoined("alice".to_string())));
+
+        state.handle_command("alice", Command::ListRooms).unwrap();
+
+        assert!(
+            received(users.get_mut("alice").unwrap())
+                .await
+                .contains(&Command::RoomList(vec![("lobby".to_string(), 2)]))
+        );
+
+        state.handle_command("alice", Command::LeaveRoom).unwrap();
+
+        assert_eq!(
+            received(users.get_mut("alice").unwrap()).await,
+            vec![Command::EndCall]
+        );
+        assert!(
+            received(users.get_mut("bob").unwrap())
+                .await
+                .contains(&Command::ParticipantLeft("alice".to_string()))
+        );
+        assert!(state.calls.room_call_id("lobby").is_some());
+
+        state.handle_command("bob", Command::LeaveRoom).unwrap();
+
+        assert!(state.calls.room_call_id("lobby").is_none());
+        assert_eq!(
+            error_code(state.handle_command("bob", Command::LeaveRoom)),
+            Some(ErrorCode::CallNotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn sender_keys_are_only_relayed_sealed_and_within_the_call() {
+        let mut state = state();
+        let mut users = join(&mut state, &["alice", "bob", "carol"]).await;
+
+        start_call(&mut state, &mut users, "alice", "bob").await;
+
+        let sender_key = |username: &str, len: usize| Command::CallSenderKey {
+            username: username.to_string(),
+            sealed_key: vec![0; len],
+        };
+
+        assert_eq!(
+            error_code(state.handle_command("alice", sender_key("bob", SEALED_SENDER_KEY_LEN - 1))),
+            Some(ErrorCode::InvalidCommand)
+        );
+        assert_eq!(
+            error_code(state.handle_command("alice", sender_key("carol", SEALED_SENDER_KEY_LEN))),
+            Some(ErrorCode::CallNotFound)
+        );
+
+        state
+            .handle_command("alice", sender_key("bob", SEALED_SENDER_KEY_LEN))
+            .unwrap();
+
+        assert_eq!(
+            received(users.get_mut("bob").unwrap()).await,
+            vec![sender_key("alice", SEALED_SENDER_KEY_LEN)]
+        );
+        assert!(received(users.get_mut("carol").unwrap()).await.is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    error::Error,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use shared::{CallRecord, identity::IDENTITY_PUBLIC_KEY_LEN};
+use tokio::task::spawn_blocking;
+
+pub mod memory;
+pub mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+pub struct User {
+    pub password_hash: Option<String>,
+    pub identity_key: Option<[u8; IDENTITY_PUBLIC_KEY_LEN]>,
+}
+
+// Everything the server keeps between restarts. Calls block on the database,
+// so async code makes them through blocking().
+pub trait Storage: Send + Sync {
+    fn user(&self, username: &str) -> Result<Option<User>, Box<dyn Error + Send + Sync>>;
+
+    // Returns false when the username already exists, a key-only user can't
+    // have a password added this way
+    fn create_password(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    // Binds the key if the username has none yet and returns whichever key is
+    // bound afterwards
+    fn bind_identity_key(
+        &self,
+        username: &str,
+        identity_key: &[u8; IDENTITY_PUBLIC_KEY_LEN],
+    ) -> Result<[u8; IDENTITY_PUBLIC_KEY_LEN], Box<dyn Error + Send + Sync>>;
+
+    // Returns false when the contact was already added
+    fn add_contact(
+        &self,
+        username: &str,
+        contact: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    // Returns false when there was no such contact
+    fn remove_contact(
+        &self,
+        username: &str,
+        contact: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    fn contacts(&self, username: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>>;
+
+    fn record_call(
+        &self,
+        username: &str,
+        record: &CallRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    // Most recent calls first
+    fn call_history(
+        &self,
+        username: &str,
+        limit: usize,
+    ) -> Result<Vec<CallRecord>, Box<dyn Error + Send + Sync>>;
+
+    fn setting(
+        &self,
+        username: &str,
+        key: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>>;
+
+    fn set_setting(
+        &self,
+        username: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    fn settings(
+        &self,
+        username: &str,
+    ) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync>>;
+}
+
+// Runs storage calls on the blocking pool so a slow disk doesn't hold up the
+// async workers
+pub async fn blocking<T, F>(
+    storage: &Arc<dyn Storage>,
+    f: F,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    T: Send + 'static,
+    F: FnOnce(&Arc<dyn Storage>) -> Result<T, Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    let storage = storage.clone();
+
+    spawn_blocking(move || f(&storage)).await?
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
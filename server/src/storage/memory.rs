@@ -0,0 +1,193 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    sync::Mutex,
+};
+
+use shared::{CallRecord, identity::IDENTITY_PUBLIC_KEY_LEN};
+
+use super::{Storage, User};
+
+#[derive(Default)]
+struct MemoryState {
+    usernames_to_password_hashes: HashMap<String, String>,
+    usernames_to_identity_keys: HashMap<String, [u8; IDENTITY_PUBLIC_KEY_LEN]>,
+    usernames_to_contacts: HashMap<String, BTreeSet<String>>,
+    usernames_to_call_history: HashMap<String, Vec<CallRecord>>,
+    usernames_to_settings: HashMap<String, BTreeMap<String, String>>,
+}
+
+// Used when no database is configured and for tests, nothing survives a restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn state(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, MemoryState>, Box<dyn Error + Send + Sync>> {
+        self.state
+            .lock()
+            .map_err(|_| "Memory storage lock was poisoned".into())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn user(&self, username: &str) -> Result<Option<User>, Box<dyn Error + Send + Sync>> {
+        let state = self.state()?;
+
+        let password_hash = state.usernames_to_password_hashes.get(username).cloned();
+        let identity_key = state.usernames_to_identity_keys.get(username).copied();
+
+        if password_hash.is_none() && identity_key.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(User {
+            password_hash,
+            identity_key,
+        }))
+    }
+
+    fn create_password(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut state = self.state()?;
+
+        if state.usernames_to_password_hashes.contains_key(username)
+            || state.usernames_to_identity_keys.contains_key(username)
+        {
+            return Ok(false);
+        }
+
+        state
+            .usernames_to_password_hashes
+            .insert(username.to_string(), password_hash.to_string());
+
+        Ok(true)
+    }
+
+    fn bind_identity_key(
+        &self,
+        username: &str,
+        identity_key: &[u8; IDENTITY_PUBLIC_KEY_LEN],
+    ) -> Result<[u8; IDENTITY_PUBLIC_KEY_LEN], Box<dyn Error + Send + Sync>> {
+        Ok(*self
+            .state()?
+            .usernames_to_identity_keys
+            .entry(username.to_string())
+            .or_insert(*identity_key))
+    }
+
+    fn add_contact(
+        &self,
+        username: &str,
+        contact: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .state()?
+            .usernames_to_contacts
+            .entry(username.to_string())
+            .or_default()
+            .insert(contact.to_string()))
+    }
+
+    fn remove_contact(
+        &self,
+        username: &str,
+        contact: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .state()?
+            .usernames_to_contacts
+            .get_mut(username)
+            .is_some_and(|contacts| contacts.remove(contact)))
+    }
+
+    fn contacts(&self, username: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .state()?
+            .usernames_to_contacts
+            .get(username)
+            .map(|contacts| contacts.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn record_call(
+        &self,
+        username: &str,
+        record: &CallRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.state()?
+            .usernames_to_call_history
+            .entry(username.to_string())
+            .or_default()
+            .push(record.clone());
+
+        Ok(())
+    }
+
+    fn call_history(
+        &self,
+        username: &str,
+        limit: usize,
+    ) -> Result<Vec<CallRecord>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .state()?
+            .usernames_to_call_history
+            .get(username)
+            .map(|records| records.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn setting(
+        &self,
+        username: &str,
+        key: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .state()?
+            .usernames_to_settings
+            .get(username)
+            .and_then(|settings| settings.get(key).cloned()))
+    }
+
+    fn set_setting(
+        &self,
+        username: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.state()?
+            .usernames_to_settings
+            .entry(username.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_string());
+
+        Ok(())
+    }
+
+    fn settings(
+        &self,
+        username: &str,
+    ) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .state()?
+            .usernames_to_settings
+            .get(username)
+            .map(|settings| {
+                settings
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
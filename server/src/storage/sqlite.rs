@@ -0,0 +1,300 @@
+use std::{error::Error, sync::Mutex};
+
+use rusqlite::{Connection, OptionalExtension, params};
+use shared::{CallRecord, identity::IDENTITY_PUBLIC_KEY_LEN};
+
+use super::{Storage, User, unix_time};
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+
+    CREATE TABLE IF NOT EXISTS users (
+        username TEXT PRIMARY KEY,
+        password_hash TEXT,
+        identity_key BLOB,
+        created_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS contacts (
+        username TEXT NOT NULL,
+        contact TEXT NOT NULL,
+        PRIMARY KEY (username, contact)
+    );
+
+    CREATE TABLE IF NOT EXISTS calls (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL,
+        room TEXT,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS calls_by_username ON calls (username, id);
+
+    CREATE TABLE IF NOT EXISTS call_peers (
+        call_id INTEGER NOT NULL REFERENCES calls (id) ON DELETE CASCADE,
+        peer TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS call_peers_by_call ON call_peers (call_id);
+
+    CREATE TABLE IF NOT EXISTS settings (
+        username TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (username, key)
+    );
+";
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<SqliteStorage, Box<dyn Error + Send + Sync>> {
+        let connection = Connection::open(path)
+            .map_err(|e| format!("Failed to open database {}: {}", path, e))?;
+
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|e| format!("Failed to create schema in {}: {}", path, e))?;
+
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, Connection>, Box<dyn Error + Send + Sync>> {
+        self.connection
+            .lock()
+            .map_err(|_| "Database lock was poisoned".into())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn user(&self, username: &str) -> Result<Option<User>, Box<dyn Error + Send + Sync>> {
+        let row = self
+            .connection()?
+            .query_row(
+                "SELECT password_hash, identity_key FROM users WHERE username = ?1",
+                params![username],
+                |row| Ok((row.get(0)?, row.get::<_, Option<Vec<u8>>>(1)?)),
+            )
+            .optional()?;
+
+        let Some((password_hash, identity_key)) = row else {
+            return Ok(None);
+        };
+
+        Ok(Some(User {
+            password_hash,
+            identity_key: identity_key
+                .map(|key| key.try_into())
+                .transpose()
+                .map_err(|_| {
+                    format!("Stored identity key for {} has the wrong length", username)
+                })?,
+        }))
+    }
+
+    fn create_password(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let changed = self.connection()?.execute(
+            "INSERT INTO users (username, password_hash, created_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (username) DO NOTHING",
+            params![username, password_hash, unix_time() as i64],
+        )?;
+
+        Ok(changed == 1)
+    }
+
+    fn bind_identity_key(
+        &self,
+        username: &str,
+        identity_key: &[u8; IDENTITY_PUBLIC_KEY_LEN],
+    ) -> Result<[u8; IDENTITY_PUBLIC_KEY_LEN], Box<dyn Error + Send + Sync>> {
+        let connection = self.connection()?;
+
+        connection.execute(
+            "INSERT INTO users (username, identity_key, created_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (username) DO UPDATE SET identity_key = excluded.identity_key
+             WHERE users.identity_key IS NULL",
+            params![username, identity_key.as_slice(), unix_time() as i64],
+        )?;
+
+        let bound_key: Vec<u8> = connection.query_row(
+            "SELECT identity_key FROM users WHERE username = ?1",
+            params![username],
+            |row| row.get(0),
+        )?;
+
+        bound_key.try_into().map_err(|_| {
+            format!("Stored identity key for {} has the wrong length", username).into()
+        })
+    }
+
+    fn add_contact(
+        &self,
+        username: &str,
+        contact: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let changed = self.connection()?.execute(
+            "INSERT OR IGNORE INTO contacts (username, contact) VALUES (?1, ?2)",
+            params![username, contact],
+        )?;
+
+        Ok(changed == 1)
+    }
+
+    fn remove_contact(
+        &self,
+        username: &str,
+        contact: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let changed = self.connection()?.execute(
+            "DELETE FROM contacts WHERE username = ?1 AND contact = ?2",
+            params![username, contact],
+        )?;
+
+        Ok(changed == 1)
+    }
+
+    fn contacts(&self, username: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let connection = self.connection()?;
+
+        let mut statement = connection
+            .prepare("SELECT contact FROM contacts WHERE username = ?1 ORDER BY contact")?;
+
+        let contacts = statement
+            .query_map(params![username], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(contacts)
+    }
+
+    fn record_call(
+        &self,
+        username: &str,
+        record: &CallRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        transaction.execute(
+            "INSERT INTO calls (username, room, started_at, ended_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                username,
+                record.room,
+                record.started_at as i64,
+                record.ended_at as i64
+            ],
+        )?;
+
+        let call_id = transaction.last_insert_rowid();
+
+        for peer in &record.peers {
+            transaction.execute(
+                "INSERT INTO call_peers (call_id, peer) VALUES (?1, ?2)",
+                params![call_id, peer],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn call_history(
+        &self,
+        username: &str,
+        limit: usize,
+    ) -> Result<Vec<CallRecord>, Box<dyn Error + Send + Sync>> {
+        let connection = self.connection()?;
+
+        let mut calls_statement = connection.prepare(
+            "SELECT id, room, started_at, ended_at FROM calls
+             WHERE username = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let mut peers_statement =
+            connection.prepare("SELECT peer FROM call_peers WHERE call_id = ?1 ORDER BY peer")?;
+
+        let calls = calls_statement
+            .query_map(params![username, limit as i64], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut records = Vec::with_capacity(calls.len());
+
+        for (call_id, room, started_at, ended_at) in calls {
+            records.push(CallRecord {
+                peers: peers_statement
+                    .query_map(params![call_id], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?,
+                room,
+                started_at: started_at as u64,
+                ended_at: ended_at as u64,
+            });
+        }
+
+        Ok(records)
+    }
+
+    fn setting(
+        &self,
+        username: &str,
+        key: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let value = self
+            .connection()?
+            .query_row(
+                "SELECT value FROM settings WHERE username = ?1 AND key = ?2",
+                params![username, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(value)
+    }
+
+    fn set_setting(
+        &self,
+        username: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.connection()?.execute(
+            "INSERT INTO settings (username, key, value) VALUES (?1, ?2, ?3)
+             ON CONFLICT (username, key) DO UPDATE SET value = excluded.value",
+            params![username, key, value],
+        )?;
+
+        Ok(())
+    }
+
+    fn settings(
+        &self,
+        username: &str,
+    ) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync>> {
+        let connection = self.connection()?;
+
+        let mut statement = connection
+            .prepare("SELECT key, value FROM settings WHERE username = ?1 ORDER BY key")?;
+
+        let settings = statement
+            .query_map(params![username], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(settings)
+    }
+}
@@ -1,121 +1,178 @@
-use std::{collections::HashMap, error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, net::SocketAddr, sync::Arc, time::Duration};
 
-use log::{error, info};
+use log::{debug, error, info};
 use shared::{
-    ADD_USER_TO_CLIENT_BYTE, DENY_CALL_BYTE, END_CALL_BYTE, HELLO_FROM_CLIENT_BYTE,
-    HELLO_FROM_SERVER_BYTE, REMOVE_USER_FROM_CLIENT_BYTE, REQUEST_CALL_BYTE,
-    REQUEST_CALL_STREAM_ID_BYTE, SEND_CALL_STREAM_ID_BYTE, START_CALL_BYTE, TCP_PORT, UDP_PORT,
-    USERNAME_ALREADY_TAKEN_BYTE, receive_command_from_stream, send_command_to_stream,
+    Capabilities, Command, CommandReader, DecodeError, ErrorCode, FRAME_ENCODING_PACKED_ASCII,
+    HEARTBEAT_INTERVAL, MAX_USERNAME_LEN, PROTOCOL_VERSION,
+    identity::{IDENTITY_PUBLIC_KEY_LEN, verify_challenge},
+    is_valid_username,
+    media::{
+        MAX_MEDIA_DATAGRAM_SIZE, MEDIA_FLAG_REGISTER, MEDIA_FLAG_REGISTERED, MediaHeader,
+        ReplayWindow, SID_LEN, control_packet, datagram_sid, open_datagram, seal_datagram,
+    },
+    send_command_to_stream, send_legacy_hello_rejected,
+    tls::{self, TlsAcceptor},
 };
 use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpListener, TcpStream, UdpSocket},
-    sync::{Mutex, broadcast},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+    sync::Semaphore,
+    time::{Instant, interval_at, timeout},
 };
 
-#[derive(Debug)]
-struct Call {
-    usernames_to_sids: HashMap<String, [u8; 4]>,
-    sids_requested: u16,
-}
+use crate::{
+    accounts::{Accounts, Login},
+    calls::{CallRegistry, MediaRoutes, Sid},
+    config::Config,
+    outbound,
+    settings::{CallPolicy, SETTINGS, call_policy, settings},
+    state::{ServerState, StateHandle},
+    storage::{MemoryStorage, SqliteStorage, Storage, blocking},
+};
+
+trait SignalingStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> SignalingStream for T {}
+
+const SERVER_CAPABILITIES: Capabilities = Capabilities {
+    frame_encodings: FRAME_ENCODING_PACKED_ASCII,
+    color: true,
+    max_width: 320,
+    max_height: 120,
+};
+
+const MAX_CONTACTS: usize = 256;
+// Registering hashes a password, so one connection can't keep the hashers busy
+const REGISTER_INTERVAL: Duration = Duration::from_secs(5);
+const CALL_HISTORY_LIMIT: usize = 20;
+
+// Clients send media continuously, so a quiet address has gone away
+const UDP_REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+const UDP_REGISTRATION_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct WeSFU {
     tcp_listener: TcpListener,
     udp_socket: UdpSocket,
+    heartbeat_timeout: Duration,
+    ring_timeout: Duration,
+    connection_slots: Arc<Semaphore>,
+    tls_acceptor: Option<TlsAcceptor>,
+    storage: Arc<dyn Storage>,
+    accounts: Arc<Accounts>,
 }
 
 impl WeSFU {
-    pub async fn new(
-        tcp_addr: String,
-        udp_addr: String,
-    ) -> Result<WeSFU, Box<dyn Error + Send + Sync>> {
-        info!("WeSFU listening on tcp: {}, udp: {}", TCP_PORT, UDP_PORT);
+    pub async fn new(config: Config) -> Result<WeSFU, Box<dyn Error + Send + Sync>> {
+        let tls_acceptor = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                Some(TlsAcceptor::from(tls::server_config(cert_path, key_path)?))
+            }
+            _ => None,
+        };
+
+        let storage: Arc<dyn Storage> = match &config.database_path {
+            Some(database_path) => {
+                info!("Storing state in {}", database_path);
+                Arc::new(SqliteStorage::open(database_path)?)
+            }
+            None => Arc::new(MemoryStorage::new()),
+        };
+
+        let accounts = Accounts::new(
+            storage.clone(),
+            config.require_accounts,
+            config.allow_registration,
+        );
+
+        info!(
+            "WeSFU listening on tcp: {}, udp: {}, tls: {}",
+            config.tcp_addr,
+            config.udp_addr,
+            tls_acceptor.is_some()
+        );
         Ok(Self {
-            tcp_listener: TcpListener::bind(tcp_addr).await?,
-            udp_socket: UdpSocket::bind(udp_addr).await?,
+            tcp_listener: TcpListener::bind(config.tcp_addr).await?,
+            udp_socket: UdpSocket::bind(config.udp_addr).await?,
+            heartbeat_timeout: config.heartbeat_timeout,
+            ring_timeout: config.ring_timeout,
+            connection_slots: Arc::new(Semaphore::new(config.max_connections)),
+            tls_acceptor,
+            storage,
+            accounts: Arc::new(accounts),
         })
     }
 
     pub async fn run(self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let username_to_tcp_command_channel: Arc<
-            Mutex<HashMap<String, broadcast::Sender<(u8, Option<String>)>>>,
-        > = Arc::new(Mutex::new(HashMap::new()));
+        let media_routes = MediaRoutes::default();
 
-        let active_calls: Arc<Mutex<Vec<Call>>> = Arc::new(Mutex::new(Vec::new()));
-        let active_calls_for_udp = active_calls.clone();
+        let state = ServerState::new(
+            CallRegistry::new(media_routes.clone()),
+            self.storage.clone(),
+            self.ring_timeout,
+        )
+        .spawn();
 
         tokio::spawn(async move {
-            if let Err(e) = udp_loop(self.udp_socket, active_calls_for_udp).await {
+            if let Err(e) = udp_loop(self.udp_socket, media_routes).await {
                 error!("UDP Error: {}", e);
             }
         });
 
         loop {
-            let username_to_tcp_command_channel = username_to_tcp_command_channel.clone();
-            let active_calls = active_calls.clone();
+            let state = state.clone();
+            let heartbeat_timeout = self.heartbeat_timeout;
+            let tls_acceptor = self.tls_acceptor.clone();
+            let storage = self.storage.clone();
+            let accounts = self.accounts.clone();
+
+            let (tcp_stream, addr) = self.tcp_listener.accept().await?;
+
+            let Ok(connection_slot) = self.connection_slots.clone().try_acquire_owned() else {
+                info!("Refused connection from {}: connection limit reached", addr);
+                continue;
+            };
 
-            let (mut stream, addr) = self.tcp_listener.accept().await?;
             info!("Opened connection from {}", addr);
 
             tokio::spawn(async move {
-                let current_username: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+                let _connection_slot = connection_slot;
+
+                let mut stream: Box<dyn SignalingStream> = match tls_acceptor {
+                    Some(tls_acceptor) => {
+                        match timeout(heartbeat_timeout, tls_acceptor.accept(tcp_stream)).await {
+                            Ok(Ok(tls_stream)) => Box::new(tls_stream),
+                            Ok(Err(e)) => {
+                                error!("TLS handshake with {} failed: {}", addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                error!("TLS handshake with {} timed out", addr);
+                                return;
+                            }
+                        }
+                    }
+                    None => Box::new(tcp_stream),
+                };
+
+                let mut current_username = None;
 
                 if let Err(e) = handle_connection(
                     &mut stream,
-                    current_username.clone(),
-                    username_to_tcp_command_channel.clone(),
-                    active_calls.clone(),
+                    &mut current_username,
+                    state.clone(),
+                    storage,
+                    accounts,
+                    heartbeat_timeout,
                 )
                 .await
                 {
                     error!("Connection error: {}", e);
                 }
 
-                if let Some(current_username) = current_username.lock().await.take() {
-                    username_to_tcp_command_channel
-                        .lock()
-                        .await
-                        .remove(&current_username);
-
-                    for (username, tcp_command_channel) in
-                        username_to_tcp_command_channel.lock().await.iter()
-                    {
-                        if let Err(e) = tcp_command_channel
-                            .send((REMOVE_USER_FROM_CLIENT_BYTE, Some(current_username.clone())))
-                        {
-                            error!(
-                                "Error removing {} from {}: {}",
-                                current_username, username, e
-                            );
-                        }
-                    }
-
-                    for call in active_calls.lock().await.iter() {
-                        if !call.usernames_to_sids.contains_key(&current_username) {
-                            continue;
-                        }
-
-                        let call_names: Vec<&String> = call.usernames_to_sids.keys().collect();
-
-                        info!("Call ended between {} and {}", call_names[0], call_names[1]);
-
-                        for username in call.usernames_to_sids.keys() {
-                            if let Some(tx) =
-                                username_to_tcp_command_channel.lock().await.get(username)
-                            {
-                                if let Err(e) = tx.send((END_CALL_BYTE, None)) {
-                                    error!("Errors end call: {}", e);
-                                }
-                            }
-                        }
-                    }
-
-                    active_calls
-                        .lock()
-                        .await
-                        .retain(|call| !call.usernames_to_sids.contains_key(&current_username));
-
-                    info!("{} has disconnected!", current_username);
+                if let Some(current_username) = current_username
+                    && let Err(e) = state.leave(&current_username).await
+                {
+                    error!("Error removing {}: {}", current_username, e);
                 }
 
                 info!("Closed connection from {}", addr);
@@ -126,211 +183,378 @@ impl WeSFU {
 
 async fn udp_loop(
     udp_socket: UdpSocket,
-    active_calls: Arc<Mutex<Vec<Call>>>,
+    media_routes: MediaRoutes,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut sids_to_udp_addrs = HashMap::new();
+    // Where each SID was last heard from, and when
+    let mut sids_to_udp_addrs: HashMap<Sid, (SocketAddr, Instant)> = HashMap::new();
+    let mut sids_to_replay_windows: HashMap<Sid, ReplayWindow> = HashMap::new();
 
-    let mut buf = [0; 4844];
+    let mut sweep = interval_at(
+        Instant::now() + UDP_REGISTRATION_SWEEP_INTERVAL,
+        UDP_REGISTRATION_SWEEP_INTERVAL,
+    );
+
+    let mut buf = [0; MAX_MEDIA_DATAGRAM_SIZE];
 
     loop {
-        let (n, addr) = udp_socket.recv_from(&mut buf).await?;
+        let (n, addr) = tokio::select! {
 
-        let sid: [u8; 4] = buf[0..4].try_into()?;
-        let message = &buf[4..n];
+            result = udp_socket.recv_from(&mut buf) => result?,
 
-        match sids_to_udp_addrs.get(&sid) {
-            Some(_) => {
-                let mut other_sid = None;
+            _ = sweep.tick() => {
 
-                let active_calls_guard = active_calls.lock().await;
+                sids_to_udp_addrs.retain(|sid, (udp_addr, last_seen)| {
+                    let keep = media_routes.get(sid).is_some()
+                        && last_seen.elapsed() < UDP_REGISTRATION_TIMEOUT;
 
-                for call in active_calls_guard.iter() {
-                    if call.usernames_to_sids.values().any(|s| s == &sid) {
-                        for other in call.usernames_to_sids.values() {
-                            if other != &sid {
-                                other_sid = Some(*other);
-                                break;
-                            }
-                        }
-                        break;
+                    if !keep {
+                        debug!("Removing UDP registration for {}", udp_addr);
                     }
-                }
 
-                if let Some(other_sid) = other_sid {
-                    if let Some(udp_addr) = sids_to_udp_addrs.get(&other_sid) {
-                        udp_socket.send_to(message, udp_addr).await?;
-                    }
-                }
+                    keep
+                });
+
+                // Replay windows live as long as the call, so packets captured
+                // before an idle timeout can't be replayed to register again
+                sids_to_replay_windows.retain(|sid, _| media_routes.get(sid).is_some());
+
+                continue;
+            }
+        };
+
+        let datagram = &buf[..n];
+
+        let Some(sid) = datagram_sid(datagram) else {
+            continue;
+        };
+
+        let Some(route) = media_routes.get(&sid) else {
+            debug!("Dropping datagram from {} for unknown SID", addr);
+            continue;
+        };
+
+        let message = match open_datagram(&route.media_key, datagram) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("Dropping datagram from {}: {}", addr, e);
+                continue;
+            }
+        };
+
+        let header = match MediaHeader::parse(message) {
+            Ok((header, _)) => header,
+            Err(e) => {
+                debug!("Dropping datagram from {}: {}", addr, e);
+                continue;
             }
+        };
+
+        if !sids_to_replay_windows
+            .entry(sid)
+            .or_default()
+            .accept(header.sequence)
+        {
+            debug!("Dropping replayed datagram from {}", addr);
+            continue;
+        }
+
+        match sids_to_udp_addrs.insert(sid, (addr, Instant::now())) {
+            // Authenticated packets from a new address mean the client's NAT
+            // mapping or network changed, so follow it
+            Some((udp_addr, _)) if udp_addr != addr => {
+                info!("UDP registration moved from {} to {}", udp_addr, addr);
+            }
+            Some(_) => {}
             None => {
-                sids_to_udp_addrs.insert(sid, addr);
+                debug!("Registered UDP address {}", addr);
+            }
+        }
+
+        if header.flags & MEDIA_FLAG_REGISTER != 0 {
+            let ack = seal_datagram(
+                &sid,
+                &route.media_key,
+                &control_packet(MEDIA_FLAG_REGISTERED, header.sequence),
+            );
+
+            if let Err(e) = udp_socket.send_to(&ack, addr).await {
+                debug!("Error acknowledging registration from {}: {}", addr, e);
+            }
+
+            continue;
+        }
+
+        // Receivers need the sender's SID to pick its frame key
+        let mut forwarded = Vec::with_capacity(SID_LEN + message.len());
+        forwarded.extend(&sid);
+        forwarded.extend(message);
+
+        for other_sid in &route.targets {
+            if let Some((udp_addr, _)) = sids_to_udp_addrs.get(other_sid)
+                && let Err(e) = udp_socket.send_to(&forwarded, udp_addr).await
+            {
+                debug!("Error forwarding datagram to {}: {}", udp_addr, e);
             }
         }
     }
 }
 
-async fn handle_connection(
-    stream: &mut TcpStream,
-    current_username: Arc<Mutex<Option<String>>>,
-    username_to_tcp_command_channel: Arc<
-        Mutex<HashMap<String, broadcast::Sender<(u8, Option<String>)>>>,
-    >,
-    active_calls: Arc<Mutex<Vec<Call>>>,
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    current_username: &mut Option<String>,
+    state: StateHandle,
+    storage: Arc<dyn Storage>,
+    accounts: Arc<Accounts>,
+    heartbeat_timeout: Duration,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let (tcp_command_channel_tx, mut tcp_command_channel_rx) = broadcast::channel(16);
+    let (outbound_tx, mut outbound_rx) = outbound::channel();
 
-    loop {
-        let tcp_command_channel_tx = tcp_command_channel_tx.clone();
+    let (read_half, mut write_half) = tokio::io::split(stream);
+    let mut reader = CommandReader::new(read_half);
+    let stream = &mut write_half;
 
+    let mut heartbeat = interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    // Only account holders can keep contacts, history and settings, anonymous
+    // names can be picked up by anyone once they disconnect
+    let mut authenticated = false;
+
+    let mut last_register: Option<Instant> = None;
+
+    loop {
         tokio::select! {
 
-            result = tcp_command_channel_rx.recv() => {
+            // Flush what is queued before reading more, so a client that
+            // sends faster than it reads is slowed down by its own socket
+            biased;
+
+            result = outbound_rx.recv() => {
 
-                let (cmd_byte, subject) = result?;
+                let command = result?;
 
-                send_command_to_stream(cmd_byte, subject, stream).await?;
+                send_command(&command, stream, heartbeat_timeout).await?;
             }
 
-            result = receive_command_from_stream(stream) => {
+            _ = heartbeat.tick() => {
 
-                match result? {
-                    Some((cmd, message)) => match cmd {
-                        HELLO_FROM_CLIENT_BYTE => {
-                            if let Some(username) = message {
+                if last_seen.elapsed() > heartbeat_timeout {
+                    return Err(format!("No heartbeat for {:?}, evicting session", heartbeat_timeout).into());
+                }
 
-                                if !username_to_tcp_command_channel.lock().await.contains_key(&username) {
-                                    *current_username.lock().await = Some(username.clone());
-                                    username_to_tcp_command_channel.lock().await.insert(username.clone(), tcp_command_channel_tx);
-                                    info!("{} has connected!", username);
+                send_command(&Command::Ping, stream, heartbeat_timeout).await?;
+            }
 
-                                    send_command_to_stream(HELLO_FROM_SERVER_BYTE, None, stream).await?;
+            result = reader.receive() => {
 
-                                    for (user, tcp_command_channel) in username_to_tcp_command_channel.lock().await.iter() {
-                                        if *user == username {
-                                            continue;
-                                        }
+                last_seen = Instant::now();
 
-                                        if active_calls.lock().await.iter().any(|x| x.usernames_to_sids.contains_key(user)) {
+                let result = match result {
+                    Err(_) if reader.is_legacy_hello() => {
 
-                                            continue;
-                                        }
+                        info!("Rejected a client using the old single byte length framing");
 
-                                        tcp_command_channel.send((ADD_USER_TO_CLIENT_BYTE, Some(username.clone())))?;
+                        let reason = format!("Unsupported protocol version, server requires version {}", PROTOCOL_VERSION);
+                        timeout(heartbeat_timeout, send_legacy_hello_rejected(&reason, stream)).await??;
 
-                                        send_command_to_stream(
-                                            ADD_USER_TO_CLIENT_BYTE,
-                                            Some(user.to_string()),
-                                            stream,
-                                        )
-                                        .await?;
-                                    }
-                                } else {
+                        return Ok(());
+                    }
+                    // The bad message was skipped whole, so the session can go on
+                    Err(e) if e.is::<DecodeError>() => {
 
-                                    info!("Username: {} was already taken", username);
+                        send_error(ErrorCode::InvalidCommand, e.to_string(), stream, heartbeat_timeout).await?;
 
-                                    send_command_to_stream(USERNAME_ALREADY_TAKEN_BYTE, None, stream)
-                                        .await?;
-                                }
-                            } else {
-                                return Err("Missing username".into());
-                            }
+                        continue;
+                    }
+                    result => result?,
+                };
+
+                match result {
+                    Some(command) => match command {
+                        Command::Ping => {
+                            send_command(&Command::Pong, stream, heartbeat_timeout).await?;
                         }
 
-                        REQUEST_CALL_BYTE | DENY_CALL_BYTE | START_CALL_BYTE => {
+                        Command::Pong => {}
+
+                        Command::HelloFromClient { version, capabilities, username, password, identity_key } => {
+
+                            // A second hello would join a second session under the first one's rights
+                            if current_username.is_some() {
+                                send_error(ErrorCode::InvalidCommand, "You are already logged in".to_string(), stream, heartbeat_timeout).await?;
+                                continue;
+                            }
+
+                            if version != PROTOCOL_VERSION {
+
+                                info!("Rejected {}: protocol version {} is not supported", username, version);
+
+                                let reason = format!("Unsupported protocol version {}, server requires version {}", version, PROTOCOL_VERSION);
+                                send_command(&Command::HelloRejected(reason), stream, heartbeat_timeout).await?;
 
-                            if let Some(current_name) = current_username.lock().await.clone() {
-                                if let Some(username) = message {
+                                return Ok(());
+                            }
+
+                            if !is_valid_username(&username) {
 
-                                    let username_to_tcp_command_channel_guard = username_to_tcp_command_channel.lock().await;
+                                info!("Rejected a hello with an invalid username");
 
-                                    if let Some(tx) = username_to_tcp_command_channel_guard.get(&username) {
+                                send_command(&Command::HelloRejected(invalid_username_reason()), stream, heartbeat_timeout).await?;
 
-                                        if cmd == START_CALL_BYTE {
+                                return Ok(());
+                            }
 
-                                            let mut usernames_to_sids = HashMap::new();
+                            let Some(negotiated_capabilities) = SERVER_CAPABILITIES.negotiate(&capabilities) else {
 
-                                            usernames_to_sids.insert(current_name.clone(), rand::random());
-                                            usernames_to_sids.insert(username.clone(), rand::random());
+                                info!("Rejected {}: no common frame encoding", username);
 
-                                            active_calls.lock().await.push(
-                                                Call {
-                                                    usernames_to_sids: usernames_to_sids,
-                                                    sids_requested: 0
-                                                }
-                                            );
+                                let reason = "No frame encoding in common with the server".to_string();
+                                send_command(&Command::HelloRejected(reason), stream, heartbeat_timeout).await?;
 
-                                            for (user, tx) in username_to_tcp_command_channel_guard.iter() {
+                                return Ok(());
+                            };
 
-                                                if *user == current_name || *user == username {
+                            if let Some(identity_key) = identity_key
+                                && !prove_identity(&username, &identity_key, &mut reader, stream, heartbeat_timeout).await?
+                            {
+
+                                info!("Rejected {}: identity proof failed", username);
+
+                                let reason = "Identity proof failed".to_string();
+                                send_command(&Command::HelloRejected(reason), stream, heartbeat_timeout).await?;
+
+                                return Ok(());
+                            }
 
-                                                    continue;
-                                                }
+                            match accounts.login(&username, password, identity_key).await? {
+                                Login::Rejected(reason) => {
 
-                                                tx.send((REMOVE_USER_FROM_CLIENT_BYTE, Some(current_name.clone())))?;
-                                                tx.send((REMOVE_USER_FROM_CLIENT_BYTE, Some(username.clone())))?;
-                                            }
+                                    info!("Rejected {}: {}", username, reason);
 
-                                            info!("Call started between {} and {}", current_name, username);
-                                        }
+                                    send_command(&Command::HelloRejected(reason), stream, heartbeat_timeout).await?;
 
-                                        tx.send((cmd, Some(current_name.clone())))?;
-                                    }
-                                    else {
-                                        return Err("Invalid username".into());
-                                    }
+                                    return Ok(());
                                 }
-                                else {
-                                    return Err(format!("Missing username {}", cmd).into());
+                                Login::Authenticated => {
+                                    info!("{} logged in with an account", username);
+                                    authenticated = true;
                                 }
+                                Login::Anonymous => {}
+                            }
+
+                            if state.join(&username, negotiated_capabilities, authenticated, outbound_tx.clone()).await? {
+                                *current_username = Some(username.clone());
+
+                                // The presence snapshot is already queued behind this
+                                send_command(&Command::HelloFromServer(negotiated_capabilities), stream, heartbeat_timeout).await?;
+                            } else {
+                                send_command(&Command::UsernameAlreadyTaken, stream, heartbeat_timeout)
+                                    .await?;
+                            }
+                        }
+
+                        Command::Register { username, password } => {
+
+                            if !accounts.allow_registration() {
+                                send_error(ErrorCode::RegistrationDisabled, "Registration is disabled on this server".to_string(), stream, heartbeat_timeout).await?;
+                                continue;
+                            }
+
+                            if !is_valid_username(&username) {
+                                send_error(ErrorCode::InvalidCommand, invalid_username_reason(), stream, heartbeat_timeout).await?;
+                                continue;
+                            }
+
+                            if last_register.is_some_and(|last_register| last_register.elapsed() < REGISTER_INTERVAL) {
+                                send_error(ErrorCode::InvalidCommand, format!("Wait {:?} between registrations", REGISTER_INTERVAL), stream, heartbeat_timeout).await?;
+                                continue;
+                            }
+
+                            last_register = Some(Instant::now());
+
+                            if accounts.register(&username, password).await? {
+
+                                info!("Registered account {}", username);
+
+                                send_command(&Command::Registered(username), stream, heartbeat_timeout).await?;
+                            } else {
+
+                                send_error(ErrorCode::AccountExists, format!("{} already has an account", username), stream, heartbeat_timeout).await?;
                             }
                         }
 
-                        REQUEST_CALL_STREAM_ID_BYTE => {
-                            if let Some(current_name) = current_username.lock().await.clone() {
-                                if let Some(username) = message {
-                                    let mut active_calls_guard = active_calls.lock().await;
-                                    let mut found_call = None;
-
-                                    for active_call in active_calls_guard.iter_mut() {
-                                        if active_call.usernames_to_sids.contains_key(&current_name) {
-                                            found_call = Some(active_call);
-                                            break;
-                                        }
-                                    }
-
-                                    match found_call {
-                                        Some(call) => {
-                                            if let Some(_) = call.usernames_to_sids.get(&username) {
-                                                call.sids_requested += 1;
-                                                if let Some(current_sid) = call.usernames_to_sids.get(&current_name) {
-
-                                                    let mut message = vec![SEND_CALL_STREAM_ID_BYTE];
-                                                    message.extend(current_sid);
-
-                                                    stream.write_all(&message).await?;
-                                                    stream.flush().await?;
-                                                }
-                                                else {
-                                                    return Err("Current user SID not found in call".into());
-                                                }
-                                            }
-                                            else {
-                                                return Err("Call does not exist".into());
-                                            }
-                                        }
-                                        None => {
-                                            return Err("Call does not exist".into());
-                                        }
-                                    }
+                        Command::RequestCall(callee) => {
+                            let Some(current_name) = current_username.clone() else {
+
+                                send_error(ErrorCode::NotLoggedIn, "You must say hello first".to_string(), stream, heartbeat_timeout).await?;
+                                continue;
+                            };
+
+                            match callee_policy(&storage, &callee, &current_name).await {
+                                Ok(policy) => state.request_call(&current_name, callee, policy).await?,
+                                Err(e) => {
+                                    let (code, reason) = internal_error(e);
+                                    send_error(code, reason, stream, heartbeat_timeout).await?;
                                 }
-                                else {
-                                    return Err(format!("Missing username {}", cmd).into());
+                            }
+                        }
+
+                        Command::InviteToCall(invitee) => {
+                            let Some(current_name) = current_username.clone() else {
+
+                                send_error(ErrorCode::NotLoggedIn, "You must say hello first".to_string(), stream, heartbeat_timeout).await?;
+                                continue;
+                            };
+
+                            match callee_policy(&storage, &invitee, &current_name).await {
+                                Ok(policy) => state.invite_to_call(&current_name, invitee, policy).await?,
+                                Err(e) => {
+                                    let (code, reason) = internal_error(e);
+                                    send_error(code, reason, stream, heartbeat_timeout).await?;
                                 }
                             }
                         }
 
-                        _ => {
-                            return Err("Invalid command".into());
+                        command @ (Command::StartCall(_)
+                        | Command::DenyCall(_)
+                        | Command::CancelCall(_)
+                        | Command::RequestCallStreamId(_)
+                        | Command::JoinRoom(_)
+                        | Command::LeaveRoom
+                        | Command::ListRooms
+                        | Command::CallPublicKey { .. }
+                        | Command::CallSenderKey { .. }
+                        | Command::SetPresence(_)) => {
+                            let Some(current_name) = current_username.clone() else {
+
+                                send_error(ErrorCode::NotLoggedIn, "You must say hello first".to_string(), stream, heartbeat_timeout).await?;
+                                continue;
+                            };
+
+                            // Replies come back on the outbound channel
+                            state.command(&current_name, command).await?;
+                        }
+
+                        command @ (Command::AddContact(_)
+                        | Command::RemoveContact(_)
+                        | Command::ListContacts
+                        | Command::ListCallHistory
+                        | Command::SetSetting { .. }
+                        | Command::ListSettings) => {
+                            let Some(current_name) = current_username.clone().filter(|_| authenticated) else {
+
+                                send_error(ErrorCode::AccountRequired, account_required_reason(&command), stream, heartbeat_timeout).await?;
+                                continue;
+                            };
+
+                            match account_command(&current_name, command, &storage, &state).await {
+                                Ok(reply) => send_command(&reply, stream, heartbeat_timeout).await?,
+                                Err((code, reason)) => send_error(code, reason, stream, heartbeat_timeout).await?,
+                            }
+                        }
+
+                        command => {
+                            send_error(ErrorCode::InvalidCommand, format!("Unexpected command {:?}", command), stream, heartbeat_timeout).await?;
                         }
                     },
                     None => return Ok(()),
@@ -339,3 +563,238 @@ async fn handle_connection(
         }
     }
 }
+
+fn invalid_username_reason() -> String {
+    format!(
+        "Usernames must be 1 to {} letters, digits, '-', '_' or '.'",
+        MAX_USERNAME_LEN
+    )
+}
+
+async fn send_error<W: AsyncWrite + Unpin>(
+    code: ErrorCode,
+    reason: String,
+    stream: &mut W,
+    write_timeout: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Replying with error {:?}: {}", code, reason);
+
+    send_command(&Command::Error { code, reason }, stream, write_timeout).await
+}
+
+// Every write to a client is bounded, so one that stops reading can't leave its
+// connection task blocked where the heartbeat check never runs
+async fn send_command<W: AsyncWrite + Unpin>(
+    command: &Command,
+    stream: &mut W,
+    write_timeout: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    timeout(write_timeout, send_command_to_stream(command, stream))
+        .await
+        .map_err(|_| {
+            format!(
+                "Client stopped reading for {:?}, evicting session",
+                write_timeout
+            )
+        })?
+}
+
+async fn callee_policy(
+    storage: &Arc<dyn Storage>,
+    callee: &str,
+    caller: &str,
+) -> Result<CallPolicy, Box<dyn Error + Send + Sync>> {
+    let callee = callee.to_string();
+    let caller = caller.to_string();
+
+    blocking(storage, move |storage| {
+        call_policy(storage, &callee, &caller)
+    })
+    .await
+}
+
+// Contacts, call history and settings, answered from storage. A storage
+// failure only fails the one command, not the session.
+async fn account_command(
+    username: &str,
+    command: Command,
+    storage: &Arc<dyn Storage>,
+    state: &StateHandle,
+) -> Result<Command, (ErrorCode, String)> {
+    let owner = username.to_string();
+
+    match command {
+        Command::AddContact(contact) => {
+            if !is_valid_username(&contact) {
+                return Err((ErrorCode::InvalidCommand, invalid_username_reason()));
+            }
+
+            if contact == username {
+                return Err((
+                    ErrorCode::InvalidCommand,
+                    "You can't add yourself as a contact".to_string(),
+                ));
+            }
+
+            let added = {
+                let contact = contact.clone();
+
+                blocking(storage, move |storage| {
+                    if storage.contacts(&owner)?.len() >= MAX_CONTACTS {
+                        return Ok(None);
+                    }
+
+                    storage.add_contact(&owner, &contact).map(Some)
+                })
+                .await
+                .map_err(internal_error)?
+            };
+
+            match added {
+                None => {
+                    return Err((
+                        ErrorCode::InvalidCommand,
+                        format!("You can have at most {} contacts", MAX_CONTACTS),
+                    ));
+                }
+                Some(true) => info!("{} added {} as a contact", username, contact),
+                Some(false) => {}
+            }
+
+            contact_list(username, storage, state).await
+        }
+        Command::RemoveContact(contact) => {
+            let removed = {
+                let contact = contact.clone();
+
+                blocking(storage, move |storage| {
+                    storage.remove_contact(&owner, &contact)
+                })
+                .await
+                .map_err(internal_error)?
+            };
+
+            if !removed {
+                return Err((
+                    ErrorCode::UserNotFound,
+                    format!("{} is not one of your contacts", contact),
+                ));
+            }
+
+            info!("{} removed {} as a contact", username, contact);
+
+            contact_list(username, storage, state).await
+        }
+        Command::ListContacts => contact_list(username, storage, state).await,
+        Command::ListCallHistory => {
+            let records = blocking(storage, move |storage| {
+                storage.call_history(&owner, CALL_HISTORY_LIMIT)
+            })
+            .await
+            .map_err(internal_error)?;
+
+            Ok(Command::CallHistory(records))
+        }
+        Command::SetSetting { key, value } => {
+            match SETTINGS.iter().find(|(name, _)| *name == key) {
+                Some((_, values)) if values.contains(&value.as_str()) => {
+                    let (key_set, value_set) = (key.clone(), value.clone());
+
+                    let settings = blocking(storage, move |storage| {
+                        storage.set_setting(&owner, &key_set, &value_set)?;
+
+                        settings(&owner, storage)
+                    })
+                    .await
+                    .map_err(internal_error)?;
+
+                    info!("{} set {} to {}", username, key, value);
+
+                    Ok(Command::Settings(settings))
+                }
+                Some((_, values)) => Err((
+                    ErrorCode::InvalidCommand,
+                    format!("{} must be one of: {}", key, values.join(", ")),
+                )),
+                None => Err((
+                    ErrorCode::InvalidCommand,
+                    format!("Unknown setting {}", key),
+                )),
+            }
+        }
+        Command::ListSettings => {
+            let settings = blocking(storage, move |storage| settings(&owner, storage))
+                .await
+                .map_err(internal_error)?;
+
+            Ok(Command::Settings(settings))
+        }
+        command => Err((
+            ErrorCode::InvalidCommand,
+            format!("Unexpected command {:?}", command),
+        )),
+    }
+}
+
+fn account_required_reason(command: &Command) -> String {
+    let feature = match command {
+        Command::ListCallHistory => "keep a call history",
+        Command::SetSetting { .. } | Command::ListSettings => "change settings",
+        _ => "keep contacts",
+    };
+
+    format!("You must log in to an account to {}", feature)
+}
+
+async fn contact_list(
+    username: &str,
+    storage: &Arc<dyn Storage>,
+    state: &StateHandle,
+) -> Result<Command, (ErrorCode, String)> {
+    let owner = username.to_string();
+
+    let contacts = blocking(storage, move |storage| storage.contacts(&owner))
+        .await
+        .map_err(internal_error)?;
+
+    let contacts = state.online(contacts).await.map_err(internal_error)?;
+
+    Ok(Command::ContactList(contacts))
+}
+
+fn internal_error(e: Box<dyn Error + Send + Sync>) -> (ErrorCode, String) {
+    error!("Internal error: {}", e);
+
+    (ErrorCode::Unknown, "Internal server error".to_string())
+}
+
+// Challenges the client to sign a fresh nonce with the identity key it sent in
+// its hello, so a public key alone can't be used to claim a username.
+async fn prove_identity<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    username: &str,
+    identity_key: &[u8; IDENTITY_PUBLIC_KEY_LEN],
+    reader: &mut CommandReader<R>,
+    stream: &mut W,
+    heartbeat_timeout: Duration,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let challenge = rand::random();
+
+    send_command(
+        &Command::IdentityChallenge(challenge),
+        stream,
+        heartbeat_timeout,
+    )
+    .await?;
+
+    match timeout(heartbeat_timeout, reader.receive()).await {
+        Ok(Ok(Some(Command::IdentityProof(signature)))) => Ok(verify_challenge(
+            identity_key,
+            &challenge,
+            username,
+            &signature,
+        )),
+        Ok(Ok(None)) => Err("Connection closed during identity challenge".into()),
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(Some(_))) | Err(_) => Ok(false),
+    }
+}
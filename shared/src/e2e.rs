@@ -0,0 +1,206 @@
+use std::error::Error;
+
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::media::SID_LEN;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SECRET_KEY_LEN: usize = 32;
+pub const SENDER_KEY_LEN: usize = 32;
+pub const E2E_TAG_LEN: usize = 16;
+// A SID and sender key sealed for one peer
+pub const SEALED_SENDER_KEY_LEN: usize = SID_LEN + SENDER_KEY_LEN + E2E_TAG_LEN;
+
+const FINGERPRINT_BYTES: usize = 10;
+const KEY_INFO: &[u8] = b"wesfu e2e pairwise key";
+
+// Every participant encrypts its frames once with a random sender key, so the
+// SFU can fan the same datagram out to everyone. Sender keys are handed to each
+// peer over a pairwise X25519 channel relayed by the server.
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public_key: [u8; PUBLIC_KEY_LEN],
+}
+
+impl KeyExchange {
+    pub fn new(secret_bytes: [u8; SECRET_KEY_LEN]) -> KeyExchange {
+        let secret = StaticSecret::from(secret_bytes);
+        let public_key = PublicKey::from(&secret).to_bytes();
+
+        KeyExchange { secret, public_key }
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.public_key
+    }
+
+    pub fn agree(
+        &self,
+        peer_public_key: [u8; PUBLIC_KEY_LEN],
+    ) -> Result<PeerChannel, Box<dyn Error + Send + Sync>> {
+        if peer_public_key == self.public_key {
+            return Err("Peer public key matches our own".into());
+        }
+
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(peer_public_key));
+
+        if !shared_secret.was_contributory() {
+            return Err("Peer public key is not valid".into());
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
+
+        let send_key = derive_key(&hkdf, &self.public_key, &peer_public_key)?;
+        let receive_key = derive_key(&hkdf, &peer_public_key, &self.public_key)?;
+
+        let (low, high) = if self.public_key < peer_public_key {
+            (self.public_key, peer_public_key)
+        } else {
+            (peer_public_key, self.public_key)
+        };
+
+        let digest = Sha256::new()
+            .chain_update(low)
+            .chain_update(high)
+            .finalize();
+
+        let fingerprint = digest[..FINGERPRINT_BYTES]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Ok(PeerChannel {
+            send: ChaCha20Poly1305::new(&send_key),
+            receive: ChaCha20Poly1305::new(&receive_key),
+            fingerprint,
+        })
+    }
+}
+
+pub struct PeerChannel {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    fingerprint: String,
+}
+
+impl PeerChannel {
+    // Each direction of a channel only ever seals our one sender key for the
+    // call, so a fixed nonce is never reused with different plaintext.
+    pub fn seal_sender_key(
+        &self,
+        sid: &[u8; SID_LEN],
+        sender_key: &[u8; SENDER_KEY_LEN],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut plaintext = Vec::with_capacity(SID_LEN + SENDER_KEY_LEN);
+        plaintext.extend(sid);
+        plaintext.extend(sender_key);
+
+        self.send
+            .encrypt(&Nonce::default(), plaintext.as_slice())
+            .map_err(|_| "Failed to seal sender key".into())
+    }
+
+    pub fn open_sender_key(
+        &self,
+        sealed_key: &[u8],
+    ) -> Result<([u8; SID_LEN], [u8; SENDER_KEY_LEN]), Box<dyn Error + Send + Sync>> {
+        let plaintext = self
+            .receive
+            .decrypt(&Nonce::default(), sealed_key)
+            .map_err(|_| "Sender key failed to decrypt")?;
+
+        if plaintext.len() != SID_LEN + SENDER_KEY_LEN {
+            return Err("Sender key has the wrong length".into());
+        }
+
+        let (sid, sender_key) = plaintext.split_at(SID_LEN);
+
+        Ok((sid.try_into()?, sender_key.try_into()?))
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+pub struct FrameCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl FrameCipher {
+    pub fn new(sender_key: &[u8; SENDER_KEY_LEN]) -> FrameCipher {
+        FrameCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(sender_key)),
+        }
+    }
+
+    pub fn encrypt(
+        &self,
+        frame_id: u32,
+        frame: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let aad = frame_id.to_be_bytes();
+
+        self.cipher
+            .encrypt(
+                &frame_nonce(frame_id),
+                Payload {
+                    msg: frame,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| "Failed to encrypt frame".into())
+    }
+
+    pub fn decrypt(
+        &self,
+        frame_id: u32,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let aad = frame_id.to_be_bytes();
+
+        self.cipher
+            .decrypt(
+                &frame_nonce(frame_id),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| "Frame failed to decrypt".into())
+    }
+}
+
+fn derive_key(
+    hkdf: &Hkdf<Sha256>,
+    sender: &[u8; PUBLIC_KEY_LEN],
+    receiver: &[u8; PUBLIC_KEY_LEN],
+) -> Result<Key, Box<dyn Error + Send + Sync>> {
+    let mut info = Vec::with_capacity(KEY_INFO.len() + 2 * PUBLIC_KEY_LEN);
+    info.extend(KEY_INFO);
+    info.extend(sender);
+    info.extend(receiver);
+
+    let mut key = Key::default();
+    hkdf.expand(&info, &mut key)
+        .map_err(|_| "Failed to derive pairwise key")?;
+
+    Ok(key)
+}
+
+// Frame ids never repeat for a sender key and every call uses fresh sender
+// keys, so the frame id alone is a unique nonce.
+fn frame_nonce(frame_id: u32) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[8..].copy_from_slice(&frame_id.to_be_bytes());
+    nonce
+}
@@ -0,0 +1,78 @@
+use std::error::Error;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+pub const IDENTITY_SEED_LEN: usize = 32;
+pub const IDENTITY_PUBLIC_KEY_LEN: usize = 32;
+pub const IDENTITY_SIGNATURE_LEN: usize = 64;
+pub const IDENTITY_CHALLENGE_LEN: usize = 32;
+
+const CHALLENGE_CONTEXT: &[u8] = b"wesfu identity challenge";
+
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn from_seed(seed: [u8; IDENTITY_SEED_LEN]) -> Identity {
+        Identity {
+            signing_key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Identity, Box<dyn Error + Send + Sync>> {
+        let seed: [u8; IDENTITY_SEED_LEN] = bytes
+            .try_into()
+            .map_err(|_| "Identity key has the wrong length")?;
+
+        Ok(Identity::from_seed(seed))
+    }
+
+    pub fn to_bytes(&self) -> [u8; IDENTITY_SEED_LEN] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; IDENTITY_PUBLIC_KEY_LEN] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn sign_challenge(
+        &self,
+        challenge: &[u8; IDENTITY_CHALLENGE_LEN],
+        username: &str,
+    ) -> [u8; IDENTITY_SIGNATURE_LEN] {
+        self.signing_key
+            .sign(&challenge_message(challenge, username))
+            .to_bytes()
+    }
+}
+
+// The username is signed along with the challenge so a proof for one name
+// can't be replayed to claim another.
+pub fn verify_challenge(
+    public_key: &[u8; IDENTITY_PUBLIC_KEY_LEN],
+    challenge: &[u8; IDENTITY_CHALLENGE_LEN],
+    username: &str,
+    signature: &[u8; IDENTITY_SIGNATURE_LEN],
+) -> bool {
+    let Ok(verifying_key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+
+    verifying_key
+        .verify(
+            &challenge_message(challenge, username),
+            &Signature::from_bytes(signature),
+        )
+        .is_ok()
+}
+
+fn challenge_message(challenge: &[u8; IDENTITY_CHALLENGE_LEN], username: &str) -> Vec<u8> {
+    let mut message =
+        Vec::with_capacity(CHALLENGE_CONTEXT.len() + IDENTITY_CHALLENGE_LEN + username.len());
+    message.extend(CHALLENGE_CONTEXT);
+    message.extend(challenge);
+    message.extend(username.as_bytes());
+    message
+}
@@ -1,79 +1,1062 @@
-use std::{error::Error, str::from_utf8};
+use std::{error::Error, fmt, str::from_utf8, time::Duration};
 
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use e2e::{PUBLIC_KEY_LEN, SEALED_SENDER_KEY_LEN};
+use identity::{IDENTITY_CHALLENGE_LEN, IDENTITY_PUBLIC_KEY_LEN, IDENTITY_SIGNATURE_LEN};
+use media::MEDIA_KEY_LEN;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub mod e2e;
+pub mod identity;
+pub mod media;
+pub mod tls;
 
 pub const TCP_PORT: u16 = 8080;
 pub const UDP_PORT: u16 = 8081;
-pub const HELLO_FROM_CLIENT_BYTE: u8 = 69;
-pub const HELLO_FROM_SERVER_BYTE: u8 = 70;
-pub const USERNAME_ALREADY_TAKEN_BYTE: u8 = 71;
-pub const ADD_USER_TO_CLIENT_BYTE: u8 = 72;
-pub const REMOVE_USER_FROM_CLIENT_BYTE: u8 = 73;
-pub const REQUEST_CALL_BYTE: u8 = 74;
-pub const START_CALL_BYTE: u8 = 75;
-pub const DENY_CALL_BYTE: u8 = 76;
-pub const END_CALL_BYTE: u8 = 77;
-pub const REQUEST_CALL_STREAM_ID_BYTE: u8 = 78;
-pub const SEND_CALL_STREAM_ID_BYTE: u8 = 79;
-
-pub async fn send_command_to_stream(
-    cmd_byte: u8,
-    subject: Option<String>,
-    stream: &mut TcpStream,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    match subject {
-        Some(subject) => {
-            if subject.len() > 255 {
-                return Err("Send Error: subject too long".into());
+pub const PROTOCOL_VERSION: u16 = 4;
+
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+pub const MAX_USERNAME_LEN: usize = 32;
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub const FRAME_ENCODING_PACKED_ASCII: u8 = 0b0000_0001;
+
+const HELLO_FROM_CLIENT_BYTE: u8 = 69;
+const HELLO_FROM_SERVER_BYTE: u8 = 70;
+const USERNAME_ALREADY_TAKEN_BYTE: u8 = 71;
+const ADD_USER_TO_CLIENT_BYTE: u8 = 72;
+const REMOVE_USER_FROM_CLIENT_BYTE: u8 = 73;
+const REQUEST_CALL_BYTE: u8 = 74;
+const START_CALL_BYTE: u8 = 75;
+const DENY_CALL_BYTE: u8 = 76;
+const END_CALL_BYTE: u8 = 77;
+const REQUEST_CALL_STREAM_ID_BYTE: u8 = 78;
+const SEND_CALL_STREAM_ID_BYTE: u8 = 79;
+const HELLO_REJECTED_BYTE: u8 = 80;
+const ERROR_BYTE: u8 = 81;
+const PING_BYTE: u8 = 82;
+const PONG_BYTE: u8 = 83;
+const CALL_PUBLIC_KEY_BYTE: u8 = 84;
+const INVITE_TO_CALL_BYTE: u8 = 85;
+const PARTICIPANT_JOINED_BYTE: u8 = 86;
+const PARTICIPANT_LEFT_BYTE: u8 = 87;
+const CALL_SENDER_KEY_BYTE: u8 = 88;
+const JOIN_ROOM_BYTE: u8 = 89;
+const LEAVE_ROOM_BYTE: u8 = 90;
+const LIST_ROOMS_BYTE: u8 = 91;
+const ROOM_LIST_BYTE: u8 = 92;
+const REGISTER_BYTE: u8 = 93;
+const REGISTERED_BYTE: u8 = 94;
+const IDENTITY_CHALLENGE_BYTE: u8 = 95;
+const IDENTITY_PROOF_BYTE: u8 = 96;
+const ADD_CONTACT_BYTE: u8 = 97;
+const REMOVE_CONTACT_BYTE: u8 = 98;
+const LIST_CONTACTS_BYTE: u8 = 99;
+const CONTACT_LIST_BYTE: u8 = 100;
+const LIST_CALL_HISTORY_BYTE: u8 = 101;
+const CALL_HISTORY_BYTE: u8 = 102;
+const SET_SETTING_BYTE: u8 = 103;
+const LIST_SETTINGS_BYTE: u8 = 104;
+const SETTINGS_BYTE: u8 = 105;
+const CALL_TIMED_OUT_BYTE: u8 = 106;
+const CANCEL_CALL_BYTE: u8 = 107;
+const BUSY_BYTE: u8 = 108;
+const CALL_WAITING_BYTE: u8 = 109;
+const SET_PRESENCE_BYTE: u8 = 110;
+const PRESENCE_CHANGED_BYTE: u8 = 111;
+const PRESENCE_SNAPSHOT_BYTE: u8 = 112;
+const PEER_IDENTITY_BYTE: u8 = 113;
+
+const MESSAGE_HEADER_LEN: usize = 5;
+const FIELD_HEADER_LEN: usize = 2;
+const CAPABILITIES_LEN: usize = 6;
+const CAPABILITY_COLOR_FLAG: u8 = 0b0000_0001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub frame_encodings: u8,
+    pub color: bool,
+    pub max_width: u16,
+    pub max_height: u16,
+}
+
+impl Capabilities {
+    pub fn negotiate(&self, other: &Capabilities) -> Option<Capabilities> {
+        let frame_encodings = self.frame_encodings & other.frame_encodings;
+        if frame_encodings == 0 {
+            return None;
+        }
+
+        Some(Capabilities {
+            frame_encodings,
+            color: self.color && other.color,
+            max_width: self.max_width.min(other.max_width),
+            max_height: self.max_height.min(other.max_height),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; CAPABILITIES_LEN] {
+        let [width_hi, width_lo] = self.max_width.to_be_bytes();
+        let [height_hi, height_lo] = self.max_height.to_be_bytes();
+        let flags = if self.color { CAPABILITY_COLOR_FLAG } else { 0 };
+
+        [
+            self.frame_encodings,
+            flags,
+            width_hi,
+            width_lo,
+            height_hi,
+            height_lo,
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Capabilities, Box<dyn Error + Send + Sync>> {
+        if bytes.len() < CAPABILITIES_LEN {
+            return Err("Decode Error: capabilities too short".into());
+        }
+
+        Ok(Capabilities {
+            frame_encodings: bytes[0],
+            color: bytes[1] & CAPABILITY_COLOR_FLAG != 0,
+            max_width: u16::from_be_bytes([bytes[2], bytes[3]]),
+            max_height: u16::from_be_bytes([bytes[4], bytes[5]]),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unknown = 0,
+    InvalidCommand = 1,
+    NotLoggedIn = 2,
+    UserNotFound = 3,
+    CallNotFound = 4,
+    IncompatibleCapabilities = 5,
+    UserInCall = 6,
+    AccountExists = 7,
+    RegistrationDisabled = 8,
+    AccountRequired = 9,
+    CallNotAllowed = 10,
+    CallAlreadyPending = 11,
+}
+
+impl ErrorCode {
+    fn from_u16(code: u16) -> ErrorCode {
+        match code {
+            1 => ErrorCode::InvalidCommand,
+            2 => ErrorCode::NotLoggedIn,
+            3 => ErrorCode::UserNotFound,
+            4 => ErrorCode::CallNotFound,
+            5 => ErrorCode::IncompatibleCapabilities,
+            6 => ErrorCode::UserInCall,
+            7 => ErrorCode::AccountExists,
+            8 => ErrorCode::RegistrationDisabled,
+            9 => ErrorCode::AccountRequired,
+            10 => ErrorCode::CallNotAllowed,
+            11 => ErrorCode::CallAlreadyPending,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+// In-call is set by the server while the user is in a call or room, clients
+// can only pick between the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Available = 0,
+    InCall = 1,
+    Away = 2,
+    DoNotDisturb = 3,
+}
+
+impl Presence {
+    fn from_u8(presence: u8) -> Result<Presence, Box<dyn Error + Send + Sync>> {
+        match presence {
+            0 => Ok(Presence::Available),
+            1 => Ok(Presence::InCall),
+            2 => Ok(Presence::Away),
+            3 => Ok(Presence::DoNotDisturb),
+            x => Err(format!("Decode Error: unknown presence {}", x).into()),
+        }
+    }
+}
+
+// Times are seconds since the unix epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallRecord {
+    pub peers: Vec<String>,
+    pub room: Option<String>,
+    pub started_at: u64,
+    pub ended_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    HelloFromClient {
+        version: u16,
+        capabilities: Capabilities,
+        username: String,
+        password: Option<String>,
+        identity_key: Option<[u8; IDENTITY_PUBLIC_KEY_LEN]>,
+    },
+    HelloFromServer(Capabilities),
+    HelloRejected(String),
+    UsernameAlreadyTaken,
+    AddUserToClient(String),
+    RemoveUserFromClient(String),
+    RequestCall(String),
+    StartCall(String),
+    DenyCall(String),
+    EndCall,
+    RequestCallStreamId(String),
+    SendCallStreamId {
+        sid: [u8; 4],
+        capabilities: Capabilities,
+        media_key: [u8; MEDIA_KEY_LEN],
+    },
+    Error {
+        code: ErrorCode,
+        reason: String,
+    },
+    Ping,
+    Pong,
+    // The username is the recipient when sent by a client and the sender when
+    // relayed by the server
+    CallPublicKey {
+        username: String,
+        public_key: [u8; PUBLIC_KEY_LEN],
+    },
+    CallSenderKey {
+        username: String,
+        sealed_key: Vec<u8>,
+    },
+    InviteToCall(String),
+    ParticipantJoined(String),
+    ParticipantLeft(String),
+    JoinRoom(String),
+    LeaveRoom,
+    ListRooms,
+    RoomList(Vec<(String, u16)>),
+    Register {
+        username: String,
+        password: String,
+    },
+    Registered(String),
+    IdentityChallenge([u8; IDENTITY_CHALLENGE_LEN]),
+    IdentityProof([u8; IDENTITY_SIGNATURE_LEN]),
+    AddContact(String),
+    RemoveContact(String),
+    ListContacts,
+    // Each contact is paired with whether they are online
+    ContactList(Vec<(String, bool)>),
+    ListCallHistory,
+    CallHistory(Vec<CallRecord>),
+    SetSetting {
+        key: String,
+        value: String,
+    },
+    ListSettings,
+    Settings(Vec<(String, String)>),
+    // Sent to both sides when a call or invite rings for too long
+    CallTimedOut(String),
+    // Withdraws a call that is still ringing, relayed to the other side
+    CancelCall(String),
+    // The callee is in another call and has call waiting turned off
+    Busy(String),
+    // Sent to both sides when the callee is in another call, the callee can
+    // answer with StartCall to hang up and switch over
+    CallWaiting(String),
+    SetPresence(Presence),
+    PresenceChanged {
+        username: String,
+        presence: Presence,
+    },
+    // Every other online user, sent right after HelloFromServer and split
+    // over several messages when there are many
+    PresenceSnapshot(Vec<(String, Presence)>),
+    // Sent before a call brings the client together with someone, verified
+    // means they logged in with a password or identity key
+    PeerIdentity {
+        username: String,
+        verified: bool,
+    },
+}
+
+pub fn is_valid_username(username: &str) -> bool {
+    is_plain_name(username, MAX_USERNAME_LEN)
+}
+
+pub fn is_valid_room_name(room: &str) -> bool {
+    is_plain_name(room, MAX_ROOM_NAME_LEN)
+}
+
+// Names end up in other users' prompts, lists and logs, so keep them short and
+// free of anything a terminal would interpret
+fn is_plain_name(name: &str, max_len: usize) -> bool {
+    (1..=max_len).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+pub fn encode(command: &Command) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let (cmd_byte, fields): (u8, Vec<Vec<u8>>) = match command {
+        Command::HelloFromClient {
+            version,
+            capabilities,
+            username,
+            password,
+            identity_key,
+        } => (
+            // The version is always the first field so any later server can read
+            // it. Clients from before this framing are caught by is_legacy_hello.
+            HELLO_FROM_CLIENT_BYTE,
+            vec![
+                version.to_be_bytes().to_vec(),
+                capabilities.to_bytes().to_vec(),
+                username.as_bytes().to_vec(),
+                password.as_deref().unwrap_or("").as_bytes().to_vec(),
+                identity_key.map(|key| key.to_vec()).unwrap_or_default(),
+            ],
+        ),
+        Command::HelloFromServer(capabilities) => (
+            HELLO_FROM_SERVER_BYTE,
+            vec![capabilities.to_bytes().to_vec()],
+        ),
+        Command::HelloRejected(reason) => (HELLO_REJECTED_BYTE, vec![reason.as_bytes().to_vec()]),
+        Command::UsernameAlreadyTaken => (USERNAME_ALREADY_TAKEN_BYTE, vec![]),
+        Command::AddUserToClient(username) => {
+            (ADD_USER_TO_CLIENT_BYTE, vec![username.as_bytes().to_vec()])
+        }
+        Command::RemoveUserFromClient(username) => (
+            REMOVE_USER_FROM_CLIENT_BYTE,
+            vec![username.as_bytes().to_vec()],
+        ),
+        Command::RequestCall(username) => (REQUEST_CALL_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::StartCall(username) => (START_CALL_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::DenyCall(username) => (DENY_CALL_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::EndCall => (END_CALL_BYTE, vec![]),
+        Command::RequestCallStreamId(username) => (
+            REQUEST_CALL_STREAM_ID_BYTE,
+            vec![username.as_bytes().to_vec()],
+        ),
+        Command::SendCallStreamId {
+            sid,
+            capabilities,
+            media_key,
+        } => (
+            SEND_CALL_STREAM_ID_BYTE,
+            vec![
+                sid.to_vec(),
+                capabilities.to_bytes().to_vec(),
+                media_key.to_vec(),
+            ],
+        ),
+        Command::Error { code, reason } => (
+            ERROR_BYTE,
+            vec![
+                (*code as u16).to_be_bytes().to_vec(),
+                reason.as_bytes().to_vec(),
+            ],
+        ),
+        Command::Ping => (PING_BYTE, vec![]),
+        Command::Pong => (PONG_BYTE, vec![]),
+        Command::CallPublicKey {
+            username,
+            public_key,
+        } => (
+            CALL_PUBLIC_KEY_BYTE,
+            vec![username.as_bytes().to_vec(), public_key.to_vec()],
+        ),
+        Command::CallSenderKey {
+            username,
+            sealed_key,
+        } => (
+            CALL_SENDER_KEY_BYTE,
+            vec![username.as_bytes().to_vec(), sealed_key.clone()],
+        ),
+        Command::InviteToCall(username) => {
+            (INVITE_TO_CALL_BYTE, vec![username.as_bytes().to_vec()])
+        }
+        Command::ParticipantJoined(username) => {
+            (PARTICIPANT_JOINED_BYTE, vec![username.as_bytes().to_vec()])
+        }
+        Command::ParticipantLeft(username) => {
+            (PARTICIPANT_LEFT_BYTE, vec![username.as_bytes().to_vec()])
+        }
+        Command::JoinRoom(room) => (JOIN_ROOM_BYTE, vec![room.as_bytes().to_vec()]),
+        Command::LeaveRoom => (LEAVE_ROOM_BYTE, vec![]),
+        Command::ListRooms => (LIST_ROOMS_BYTE, vec![]),
+        Command::Register { username, password } => (
+            REGISTER_BYTE,
+            vec![username.as_bytes().to_vec(), password.as_bytes().to_vec()],
+        ),
+        Command::Registered(username) => (REGISTERED_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::IdentityChallenge(challenge) => {
+            (IDENTITY_CHALLENGE_BYTE, vec![challenge.to_vec()])
+        }
+        Command::IdentityProof(signature) => (IDENTITY_PROOF_BYTE, vec![signature.to_vec()]),
+        Command::AddContact(username) => (ADD_CONTACT_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::RemoveContact(username) => {
+            (REMOVE_CONTACT_BYTE, vec![username.as_bytes().to_vec()])
+        }
+        Command::ListContacts => (LIST_CONTACTS_BYTE, vec![]),
+        Command::ContactList(contacts) => (
+            CONTACT_LIST_BYTE,
+            contacts
+                .iter()
+                .flat_map(|(username, online)| [username.as_bytes().to_vec(), vec![*online as u8]])
+                .collect(),
+        ),
+        Command::ListCallHistory => (LIST_CALL_HISTORY_BYTE, vec![]),
+        Command::CallHistory(records) => {
+            let mut fields = Vec::with_capacity(records.len() * 4);
+
+            for record in records {
+                fields.push(record.started_at.to_be_bytes().to_vec());
+                fields.push(record.ended_at.to_be_bytes().to_vec());
+                fields.push(record.room.as_deref().unwrap_or("").as_bytes().to_vec());
+                // Peers are nested fields inside a single field
+                fields.push(join_fields(
+                    record.peers.iter().map(|peer| peer.as_bytes().to_vec()),
+                )?);
+            }
+
+            (CALL_HISTORY_BYTE, fields)
+        }
+        Command::SetSetting { key, value } => (
+            SET_SETTING_BYTE,
+            vec![key.as_bytes().to_vec(), value.as_bytes().to_vec()],
+        ),
+        Command::ListSettings => (LIST_SETTINGS_BYTE, vec![]),
+        Command::Settings(settings) => (
+            SETTINGS_BYTE,
+            settings
+                .iter()
+                .flat_map(|(key, value)| [key.as_bytes().to_vec(), value.as_bytes().to_vec()])
+                .collect(),
+        ),
+        Command::CallTimedOut(username) => {
+            (CALL_TIMED_OUT_BYTE, vec![username.as_bytes().to_vec()])
+        }
+        Command::CancelCall(username) => (CANCEL_CALL_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::Busy(username) => (BUSY_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::CallWaiting(username) => (CALL_WAITING_BYTE, vec![username.as_bytes().to_vec()]),
+        Command::SetPresence(presence) => (SET_PRESENCE_BYTE, vec![vec![*presence as u8]]),
+        Command::PresenceChanged { username, presence } => (
+            PRESENCE_CHANGED_BYTE,
+            vec![username.as_bytes().to_vec(), vec![*presence as u8]],
+        ),
+        Command::PresenceSnapshot(presences) => (
+            PRESENCE_SNAPSHOT_BYTE,
+            presences
+                .iter()
+                .flat_map(|(username, presence)| {
+                    [username.as_bytes().to_vec(), vec![*presence as u8]]
+                })
+                .collect(),
+        ),
+        Command::PeerIdentity { username, verified } => (
+            PEER_IDENTITY_BYTE,
+            vec![username.as_bytes().to_vec(), vec![*verified as u8]],
+        ),
+        Command::RoomList(rooms) => (
+            ROOM_LIST_BYTE,
+            rooms
+                .iter()
+                .flat_map(|(room, participants)| {
+                    [
+                        room.as_bytes().to_vec(),
+                        participants.to_be_bytes().to_vec(),
+                    ]
+                })
+                .collect(),
+        ),
+    };
+
+    let payload = join_fields(fields)?;
+
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err("Send Error: message too long".into());
+    }
+
+    let mut message_bytes = vec![cmd_byte];
+    message_bytes.extend((payload.len() as u32).to_be_bytes());
+    message_bytes.extend(payload);
+
+    Ok(message_bytes)
+}
+
+// A whole message arrived but could not be decoded. It has already been
+// consumed, so the reader is still in step with the stream.
+#[derive(Debug)]
+pub struct DecodeError(String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for DecodeError {}
+
+pub fn decode(cmd_byte: u8, payload: &[u8]) -> Result<Command, Box<dyn Error + Send + Sync>> {
+    let fields = split_fields(payload)?;
+
+    let field = |index: usize| -> Result<&[u8], Box<dyn Error + Send + Sync>> {
+        fields
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("Decode Error: missing field {} for {}", index, cmd_byte).into())
+    };
+
+    let subject = |index: usize| -> Result<String, Box<dyn Error + Send + Sync>> {
+        let bytes = field(index)?;
+        if bytes.is_empty() {
+            return Err(format!("Decode Error: missing subject for {}", cmd_byte).into());
+        }
+        Ok(from_utf8(bytes)?.to_string())
+    };
+
+    let presence = |index: usize| -> Result<Presence, Box<dyn Error + Send + Sync>> {
+        match field(index)? {
+            [presence] => Presence::from_u8(*presence),
+            _ => Err(format!("Decode Error: malformed presence for {}", cmd_byte).into()),
+        }
+    };
+
+    let command = match cmd_byte {
+        HELLO_FROM_CLIENT_BYTE => Command::HelloFromClient {
+            version: u16::from_be_bytes(field(0)?.try_into()?),
+            capabilities: Capabilities::from_bytes(field(1)?)?,
+            username: subject(2)?,
+            // Anonymous hellos leave the password and key empty or omit them
+            password: match fields.get(3) {
+                Some(bytes) if !bytes.is_empty() => Some(from_utf8(bytes)?.to_string()),
+                _ => None,
+            },
+            identity_key: match fields.get(4) {
+                Some(bytes) if !bytes.is_empty() => Some((*bytes).try_into()?),
+                _ => None,
+            },
+        },
+        HELLO_FROM_SERVER_BYTE => Command::HelloFromServer(Capabilities::from_bytes(field(0)?)?),
+        HELLO_REJECTED_BYTE => Command::HelloRejected(subject(0)?),
+        USERNAME_ALREADY_TAKEN_BYTE => Command::UsernameAlreadyTaken,
+        ADD_USER_TO_CLIENT_BYTE => Command::AddUserToClient(subject(0)?),
+        REMOVE_USER_FROM_CLIENT_BYTE => Command::RemoveUserFromClient(subject(0)?),
+        REQUEST_CALL_BYTE => Command::RequestCall(subject(0)?),
+        START_CALL_BYTE => Command::StartCall(subject(0)?),
+        DENY_CALL_BYTE => Command::DenyCall(subject(0)?),
+        END_CALL_BYTE => Command::EndCall,
+        REQUEST_CALL_STREAM_ID_BYTE => Command::RequestCallStreamId(subject(0)?),
+        SEND_CALL_STREAM_ID_BYTE => Command::SendCallStreamId {
+            sid: field(0)?.try_into()?,
+            capabilities: Capabilities::from_bytes(field(1)?)?,
+            media_key: field(2)?.try_into()?,
+        },
+        ERROR_BYTE => Command::Error {
+            code: ErrorCode::from_u16(u16::from_be_bytes(field(0)?.try_into()?)),
+            reason: subject(1)?,
+        },
+        PING_BYTE => Command::Ping,
+        PONG_BYTE => Command::Pong,
+        CALL_PUBLIC_KEY_BYTE => Command::CallPublicKey {
+            username: subject(0)?,
+            public_key: field(1)?.try_into()?,
+        },
+        CALL_SENDER_KEY_BYTE => {
+            let sealed_key = field(1)?;
+
+            // Relaying swaps in a longer username, so the key can't be allowed
+            // to push the message over the size limit
+            if sealed_key.len() != SEALED_SENDER_KEY_LEN {
+                return Err("Decode Error: sealed sender key has the wrong length".into());
+            }
+
+            Command::CallSenderKey {
+                username: subject(0)?,
+                sealed_key: sealed_key.to_vec(),
+            }
+        }
+        INVITE_TO_CALL_BYTE => Command::InviteToCall(subject(0)?),
+        PARTICIPANT_JOINED_BYTE => Command::ParticipantJoined(subject(0)?),
+        PARTICIPANT_LEFT_BYTE => Command::ParticipantLeft(subject(0)?),
+        JOIN_ROOM_BYTE => Command::JoinRoom(subject(0)?),
+        LEAVE_ROOM_BYTE => Command::LeaveRoom,
+        LIST_ROOMS_BYTE => Command::ListRooms,
+        REGISTER_BYTE => Command::Register {
+            username: subject(0)?,
+            password: subject(1)?,
+        },
+        REGISTERED_BYTE => Command::Registered(subject(0)?),
+        IDENTITY_CHALLENGE_BYTE => Command::IdentityChallenge(field(0)?.try_into()?),
+        IDENTITY_PROOF_BYTE => Command::IdentityProof(field(0)?.try_into()?),
+        ADD_CONTACT_BYTE => Command::AddContact(subject(0)?),
+        REMOVE_CONTACT_BYTE => Command::RemoveContact(subject(0)?),
+        LIST_CONTACTS_BYTE => Command::ListContacts,
+        CONTACT_LIST_BYTE => {
+            let mut contacts = Vec::new();
+
+            for index in (0..fields.len()).step_by(2) {
+                contacts.push((subject(index)?, field(index + 1)? != [0]));
+            }
+
+            Command::ContactList(contacts)
+        }
+        LIST_CALL_HISTORY_BYTE => Command::ListCallHistory,
+        CALL_HISTORY_BYTE => {
+            let mut records = Vec::new();
+
+            for index in (0..fields.len()).step_by(4) {
+                let room = field(index + 2)?;
+
+                records.push(CallRecord {
+                    peers: split_fields(field(index + 3)?)?
+                        .into_iter()
+                        .map(|peer| Ok(from_utf8(peer)?.to_string()))
+                        .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?,
+                    room: if room.is_empty() {
+                        None
+                    } else {
+                        Some(from_utf8(room)?.to_string())
+                    },
+                    started_at: u64::from_be_bytes(field(index)?.try_into()?),
+                    ended_at: u64::from_be_bytes(field(index + 1)?.try_into()?),
+                });
             }
 
-            let mut message_bytes = vec![cmd_byte, subject.len() as u8];
-            message_bytes.extend(subject.as_bytes());
+            Command::CallHistory(records)
+        }
+        SET_SETTING_BYTE => Command::SetSetting {
+            key: subject(0)?,
+            value: subject(1)?,
+        },
+        LIST_SETTINGS_BYTE => Command::ListSettings,
+        SETTINGS_BYTE => {
+            let mut settings = Vec::new();
+
+            for index in (0..fields.len()).step_by(2) {
+                settings.push((subject(index)?, subject(index + 1)?));
+            }
+
+            Command::Settings(settings)
+        }
+        CALL_TIMED_OUT_BYTE => Command::CallTimedOut(subject(0)?),
+        CANCEL_CALL_BYTE => Command::CancelCall(subject(0)?),
+        BUSY_BYTE => Command::Busy(subject(0)?),
+        CALL_WAITING_BYTE => Command::CallWaiting(subject(0)?),
+        SET_PRESENCE_BYTE => Command::SetPresence(presence(0)?),
+        PRESENCE_CHANGED_BYTE => Command::PresenceChanged {
+            username: subject(0)?,
+            presence: presence(1)?,
+        },
+        PRESENCE_SNAPSHOT_BYTE => {
+            let mut presences = Vec::new();
+
+            for index in (0..fields.len()).step_by(2) {
+                presences.push((subject(index)?, presence(index + 1)?));
+            }
 
-            stream.write_all(&message_bytes).await?;
-            stream.flush().await?;
+            Command::PresenceSnapshot(presences)
         }
-        None => {
-            stream.write_all(&[cmd_byte]).await?;
-            stream.flush().await?;
+        PEER_IDENTITY_BYTE => Command::PeerIdentity {
+            username: subject(0)?,
+            verified: field(1)? != [0],
+        },
+        ROOM_LIST_BYTE => {
+            let mut rooms = Vec::new();
+
+            for index in (0..fields.len()).step_by(2) {
+                rooms.push((
+                    subject(index)?,
+                    u16::from_be_bytes(field(index + 1)?.try_into()?),
+                ));
+            }
+
+            Command::RoomList(rooms)
         }
+        x => return Err(format!("Decode Error: unknown command {}", x).into()),
+    };
+
+    Ok(command)
+}
+
+fn join_fields(
+    fields: impl IntoIterator<Item = Vec<u8>>,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut payload = Vec::new();
+
+    for field in fields {
+        let len = u16::try_from(field.len()).map_err(|_| "Send Error: field too long")?;
+        payload.extend(len.to_be_bytes());
+        payload.extend(field);
+    }
+
+    Ok(payload)
+}
+
+fn split_fields(mut payload: &[u8]) -> Result<Vec<&[u8]>, Box<dyn Error + Send + Sync>> {
+    let mut fields = Vec::new();
+
+    while !payload.is_empty() {
+        if payload.len() < FIELD_HEADER_LEN {
+            return Err("Decode Error: truncated field header".into());
+        }
+
+        let len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let rest = &payload[FIELD_HEADER_LEN..];
+
+        if rest.len() < len {
+            return Err("Decode Error: truncated field".into());
+        }
+
+        let (field, rest) = rest.split_at(len);
+        fields.push(field);
+        payload = rest;
+    }
+
+    Ok(fields)
+}
+
+pub async fn send_command_to_stream<W: AsyncWrite + Unpin>(
+    command: &Command,
+    stream: &mut W,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let message_bytes = encode(command)?;
+
+    stream.write_all(&message_bytes).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+// Rejects a client that still uses the old framing of a single length byte
+// and a bare payload, which is all it can decode
+pub async fn send_legacy_hello_rejected<W: AsyncWrite + Unpin>(
+    reason: &str,
+    stream: &mut W,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut end = reason.len().min(u8::MAX as usize);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut message_bytes = vec![HELLO_REJECTED_BYTE, end as u8];
+    message_bytes.extend(&reason.as_bytes()[..end]);
+
+    stream.write_all(&message_bytes).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+pub struct CommandReader<R> {
+    stream: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> CommandReader<R> {
+    pub fn new(stream: R) -> CommandReader<R> {
+        CommandReader {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    // Partially received messages stay buffered, so this is safe to use in `select!`
+    pub async fn receive(&mut self) -> Result<Option<Command>, Box<dyn Error + Send + Sync>> {
+        loop {
+            if let Some(command) = self.take_buffered_command()? {
+                return Ok(Some(command));
+            }
+
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err("Receive Error: connection closed mid-message".into());
+            }
+        }
+    }
+
+    // Old clients send a single non zero length byte after the command byte,
+    // where the u32 header always starts with a zero byte because messages
+    // are at most MAX_MESSAGE_SIZE. Short hellos are caught once complete.
+    pub fn is_legacy_hello(&self) -> bool {
+        self.buf.len() >= 2
+            && self.buf[0] == HELLO_FROM_CLIENT_BYTE
+            && self.buf[1] != 0
+            && (self.buf.len() >= MESSAGE_HEADER_LEN || self.buf[1] as usize == self.buf.len() - 2)
     }
 
-    return Ok(());
+    fn take_buffered_command(&mut self) -> Result<Option<Command>, Box<dyn Error + Send + Sync>> {
+        if self.buf.len() < MESSAGE_HEADER_LEN {
+            // Otherwise an old client with a short hello waits for bytes that never come
+            if self.is_legacy_hello() {
+                return Err("Receive Error: hello uses the old framing".into());
+            }
+
+            return Ok(None);
+        }
+
+        let cmd_byte = self.buf[0];
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+
+        if len > MAX_MESSAGE_SIZE {
+            return Err(format!("Receive Error: message of {} bytes is too long", len).into());
+        }
+
+        if self.buf.len() < MESSAGE_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let message: Vec<u8> = self.buf.drain(..MESSAGE_HEADER_LEN + len).collect();
+
+        let command = decode(cmd_byte, &message[MESSAGE_HEADER_LEN..])
+            .map_err(|e| DecodeError(e.to_string()))?;
+
+        Ok(Some(command))
+    }
 }
 
-pub async fn receive_command_from_stream(
-    stream: &mut TcpStream,
-) -> Result<Option<(u8, Option<String>)>, Box<dyn Error + Send + Sync>> {
-    let mut header = [0u8; 1];
-    if stream.read_exact(&mut header).await.is_err() {
-        return Ok(None);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPABILITIES: Capabilities = Capabilities {
+        frame_encodings: FRAME_ENCODING_PACKED_ASCII,
+        color: true,
+        max_width: 90,
+        max_height: 28,
+    };
+
+    async fn read_all(bytes: &[u8]) -> Vec<Result<Command, String>> {
+        let mut reader = CommandReader::new(bytes);
+        let mut commands = Vec::new();
+
+        loop {
+            match reader.receive().await {
+                Ok(Some(command)) => commands.push(Ok(command)),
+                Ok(None) => return commands,
+                Err(e) if e.is::<DecodeError>() => commands.push(Err(e.to_string())),
+                Err(e) => {
+                    commands.push(Err(e.to_string()));
+                    return commands;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_survive_an_encode_and_decode() {
+        let commands = [
+            Command::HelloFromClient {
+                version: PROTOCOL_VERSION,
+                capabilities: CAPABILITIES,
+                username: "alice".to_string(),
+                password: Some("secret".to_string()),
+                identity_key: Some([7; IDENTITY_PUBLIC_KEY_LEN]),
+            },
+            Command::HelloFromClient {
+                version: PROTOCOL_VERSION,
+                capabilities: CAPABILITIES,
+                username: "bob".to_string(),
+                password: None,
+                identity_key: None,
+            },
+            Command::HelloFromServer(CAPABILITIES),
+            Command::SendCallStreamId {
+                sid: [1, 2, 3, 4],
+                capabilities: CAPABILITIES,
+                media_key: [9; MEDIA_KEY_LEN],
+            },
+            Command::Error {
+                code: ErrorCode::CallNotAllowed,
+                reason: "no".to_string(),
+            },
+            Command::Ping,
+            Command::RoomList(vec![("lobby".to_string(), 3), ("quiet".to_string(), 1)]),
+            Command::ContactList(vec![
+                ("bob".to_string(), true),
+                ("carol".to_string(), false),
+            ]),
+            Command::CallHistory(vec![
+                CallRecord {
+                    peers: vec!["bob".to_string(), "carol".to_string()],
+                    room: None,
+                    started_at: 10,
+                    ended_at: 20,
+                },
+                CallRecord {
+                    peers: Vec::new(),
+                    room: Some("lobby".to_string()),
+                    started_at: 30,
+                    ended_at: 40,
+                },
+            ]),
+            Command::Settings(vec![("call_waiting".to_string(), "off".to_string())]),
+            Command::PresenceSnapshot(vec![
+                ("bob".to_string(), Presence::Away),
+                ("carol".to_string(), Presence::InCall),
+            ]),
+            Command::PresenceSnapshot(Vec::new()),
+            Command::PeerIdentity {
+                username: "bob".to_string(),
+                verified: true,
+            },
+        ];
+
+        let mut bytes = Vec::new();
+
+        for command in &commands {
+            bytes.extend(encode(command).unwrap());
+        }
+
+        let decoded: Vec<Command> = read_all(&bytes)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(decoded, commands);
+    }
+
+    #[test]
+    fn sender_keys_must_be_sealed_to_their_fixed_length() {
+        for len in [
+            0,
+            SEALED_SENDER_KEY_LEN - 1,
+            SEALED_SENDER_KEY_LEN + 1,
+            60_000,
+        ] {
+            let command = Command::CallSenderKey {
+                username: "bob".to_string(),
+                sealed_key: vec![0; len],
+            };
+
+            let message = encode(&command).unwrap();
+
+            assert!(
+                decode(message[0], &message[MESSAGE_HEADER_LEN..]).is_err(),
+                "{}",
+                len
+            );
+        }
+
+        let command = Command::CallSenderKey {
+            username: "bob".to_string(),
+            sealed_key: vec![0; SEALED_SENDER_KEY_LEN],
+        };
+
+        let message = encode(&command).unwrap();
+
+        assert_eq!(
+            decode(message[0], &message[MESSAGE_HEADER_LEN..]).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn encode_refuses_messages_over_the_size_limit() {
+        let command = Command::RoomList(vec![("r".repeat(1000), 1); 100]);
+
+        assert!(encode(&command).is_err());
+    }
+
+    #[tokio::test]
+    async fn reader_stays_in_step_after_a_message_it_cannot_decode() {
+        let mut bytes = vec![200];
+        bytes.extend(4u32.to_be_bytes());
+        bytes.extend(join_fields([b"ab".to_vec()]).unwrap());
+        bytes.extend(encode(&Command::Ping).unwrap());
+
+        let commands = read_all(&bytes).await;
+
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].is_err());
+        assert_eq!(commands[1], Ok(Command::Ping));
+    }
+
+    #[tokio::test]
+    async fn reader_rejects_messages_over_the_size_limit() {
+        let mut bytes = vec![PING_BYTE];
+        bytes.extend((MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes());
+
+        let mut reader = CommandReader::new(bytes.as_slice());
+
+        let error = reader.receive().await.unwrap_err();
+
+        assert!(!error.is::<DecodeError>());
+    }
+
+    #[tokio::test]
+    async fn old_framing_hellos_are_recognised() {
+        for username in ["a", "ab", "alice"] {
+            let mut bytes = vec![HELLO_FROM_CLIENT_BYTE, username.len() as u8];
+            bytes.extend(username.as_bytes());
+
+            let mut reader = CommandReader::new(bytes.as_slice());
+
+            assert!(reader.receive().await.is_err(), "{}", username);
+            assert!(reader.is_legacy_hello(), "{}", username);
+        }
+    }
+
+    #[test]
+    fn new_framing_hellos_are_not_legacy() {
+        let hello = encode(&Command::HelloFromClient {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+            username: "a".to_string(),
+            password: None,
+            identity_key: None,
+        })
+        .unwrap();
+
+        // Every prefix, as it might sit in the buffer mid read
+        for len in 1..=hello.len() {
+            let reader = CommandReader {
+                stream: &[][..],
+                buf: hello[..len].to_vec(),
+            };
+
+            assert!(!reader.is_legacy_hello(), "prefix of {} bytes", len);
+        }
     }
-    let cmd = header[0];
 
-    if matches!(
-        cmd,
-        ADD_USER_TO_CLIENT_BYTE
-            | REMOVE_USER_FROM_CLIENT_BYTE
-            | HELLO_FROM_CLIENT_BYTE
-            | REQUEST_CALL_BYTE
-            | START_CALL_BYTE
-            | DENY_CALL_BYTE
-            | REQUEST_CALL_STREAM_ID_BYTE
-    ) {
-        let mut len_buf = [0u8; 1];
-        stream.read_exact(&mut len_buf).await?;
-        let len = len_buf[0] as usize;
+    #[test]
+    fn room_names_are_short_and_plain() {
+        assert!(is_valid_room_name("lobby"));
+        assert!(!is_valid_room_name(""));
+        assert!(!is_valid_room_name("\x1b]0;pwned\x07"));
+        assert!(!is_valid_room_name(&"r".repeat(MAX_ROOM_NAME_LEN + 1)));
+    }
 
-        let mut subject_buf = vec![0u8; len];
-        stream.read_exact(&mut subject_buf).await?;
+    #[test]
+    fn usernames_are_short_and_plain() {
+        for username in [
+            "a",
+            "alice",
+            "bob-2",
+            "c_d.e",
+            &"x".repeat(MAX_USERNAME_LEN),
+        ] {
+            assert!(is_valid_username(username), "{}", username);
+        }
 
-        let subject = from_utf8(&subject_buf)?.to_string();
-        Ok(Some((cmd, Some(subject))))
-    } else {
-        Ok(Some((cmd, None)))
+        for username in [
+            "",
+            "a b",
+            "tab\t",
+            "esc\x1b[2J",
+            "émile",
+            &"x".repeat(MAX_USERNAME_LEN + 1),
+        ] {
+            assert!(!is_valid_username(username), "{}", username);
+        }
     }
 }
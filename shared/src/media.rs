@@ -0,0 +1,492 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const MEDIA_VERSION: u8 = 2;
+pub const MEDIA_HEADER_LEN: usize = 22;
+
+pub const MAX_FRAGMENT_PAYLOAD: usize = 1200;
+pub const MAX_MEDIA_PACKET_SIZE: usize = MEDIA_HEADER_LEN + MAX_FRAGMENT_PAYLOAD;
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub const SID_LEN: usize = 4;
+pub const MEDIA_KEY_LEN: usize = 32;
+pub const MEDIA_MAC_LEN: usize = 16;
+pub const MAX_MEDIA_DATAGRAM_SIZE: usize = SID_LEN + MAX_MEDIA_PACKET_SIZE + MEDIA_MAC_LEN;
+
+// Flags on packets that carry no frame. Clients register their UDP address
+// and the server echoes the sequence back so they know the path works both ways.
+pub const MEDIA_FLAG_REGISTER: u8 = 0b0000_0001;
+pub const MEDIA_FLAG_REGISTERED: u8 = 0b0000_0010;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub sequence: u32,
+    pub frame_id: u32,
+    pub timestamp_micros: u64,
+    pub fragment_index: u16,
+    pub fragment_count: u16,
+}
+
+impl MediaHeader {
+    pub fn to_bytes(&self) -> [u8; MEDIA_HEADER_LEN] {
+        let mut bytes = [0u8; MEDIA_HEADER_LEN];
+
+        bytes[0] = self.version;
+        bytes[1] = self.flags;
+        bytes[2..6].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[6..10].copy_from_slice(&self.frame_id.to_be_bytes());
+        bytes[10..18].copy_from_slice(&self.timestamp_micros.to_be_bytes());
+        bytes[18..20].copy_from_slice(&self.fragment_index.to_be_bytes());
+        bytes[20..22].copy_from_slice(&self.fragment_count.to_be_bytes());
+
+        bytes
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<(MediaHeader, &[u8]), Box<dyn Error + Send + Sync>> {
+        if packet.len() < MEDIA_HEADER_LEN {
+            return Err("Media Error: packet shorter than header".into());
+        }
+
+        if packet[0] != MEDIA_VERSION {
+            return Err(format!("Media Error: unsupported media version {}", packet[0]).into());
+        }
+
+        let header = MediaHeader {
+            version: packet[0],
+            flags: packet[1],
+            sequence: u32::from_be_bytes(packet[2..6].try_into()?),
+            frame_id: u32::from_be_bytes(packet[6..10].try_into()?),
+            timestamp_micros: u64::from_be_bytes(packet[10..18].try_into()?),
+            fragment_index: u16::from_be_bytes(packet[18..20].try_into()?),
+            fragment_count: u16::from_be_bytes(packet[20..22].try_into()?),
+        };
+
+        if header.fragment_count == 0 || header.fragment_index >= header.fragment_count {
+            return Err("Media Error: invalid fragment index".into());
+        }
+
+        Ok((header, &packet[MEDIA_HEADER_LEN..]))
+    }
+
+    // Serial number comparison so ids keep ordering correctly after wrapping
+    pub fn is_newer_than(&self, frame_id: u32) -> bool {
+        is_newer_frame(self.frame_id, frame_id)
+    }
+}
+
+fn is_newer_frame(frame_id: u32, than: u32) -> bool {
+    (frame_id.wrapping_sub(than) as i32) > 0
+}
+
+pub fn fragment_frame(
+    frame: &[u8],
+    frame_id: u32,
+    next_sequence: &mut u32,
+    flags: u8,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let timestamp_micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+
+    let chunks: Vec<&[u8]> = if frame.is_empty() {
+        vec![frame]
+    } else {
+        frame.chunks(MAX_FRAGMENT_PAYLOAD).collect()
+    };
+
+    let fragment_count =
+        u16::try_from(chunks.len()).map_err(|_| "Media Error: frame has too many fragments")?;
+
+    let mut packets = Vec::with_capacity(chunks.len());
+
+    for (fragment_index, chunk) in chunks.into_iter().enumerate() {
+        let header = MediaHeader {
+            version: MEDIA_VERSION,
+            flags,
+            sequence: *next_sequence,
+            frame_id,
+            timestamp_micros,
+            fragment_index: fragment_index as u16,
+            fragment_count,
+        };
+        *next_sequence = next_sequence.wrapping_add(1);
+
+        let mut packet = Vec::with_capacity(MEDIA_HEADER_LEN + chunk.len());
+        packet.extend(header.to_bytes());
+        packet.extend(chunk);
+        packets.push(packet);
+    }
+
+    Ok(packets)
+}
+
+pub fn control_packet(flags: u8, sequence: u32) -> Vec<u8> {
+    let timestamp_micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+
+    let header = MediaHeader {
+        version: MEDIA_VERSION,
+        flags,
+        sequence,
+        frame_id: 0,
+        timestamp_micros,
+        fragment_index: 0,
+        fragment_count: 1,
+    };
+
+    header.to_bytes().to_vec()
+}
+
+struct PartialFrame {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    first_seen: Instant,
+}
+
+pub struct FrameReassembler {
+    partial_frames: HashMap<u32, PartialFrame>,
+    last_rendered_frame_id: Option<u32>,
+    timeout: Duration,
+}
+
+impl FrameReassembler {
+    pub fn new(timeout: Duration) -> FrameReassembler {
+        FrameReassembler {
+            partial_frames: HashMap::new(),
+            last_rendered_frame_id: None,
+            timeout,
+        }
+    }
+
+    // Returns the whole frame once its last fragment arrives, dropping anything older than the
+    // newest frame already rendered
+    pub fn push(&mut self, header: &MediaHeader, payload: &[u8]) -> Option<Vec<u8>> {
+        let timeout = self.timeout;
+        self.partial_frames
+            .retain(|_, partial| partial.first_seen.elapsed() < timeout);
+
+        if let Some(last_rendered_frame_id) = self.last_rendered_frame_id
+            && !header.is_newer_than(last_rendered_frame_id)
+        {
+            return None;
+        }
+
+        let partial = self
+            .partial_frames
+            .entry(header.frame_id)
+            .or_insert_with(|| PartialFrame {
+                fragments: vec![None; header.fragment_count as usize],
+                received: 0,
+                first_seen: Instant::now(),
+            });
+
+        let slot = partial.fragments.get_mut(header.fragment_index as usize)?;
+        if slot.is_none() {
+            *slot = Some(payload.to_vec());
+            partial.received += 1;
+        }
+
+        if partial.received < partial.fragments.len() {
+            return None;
+        }
+
+        let partial = self.partial_frames.remove(&header.frame_id)?;
+
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    // Called once a frame from push has been authenticated, so a forged frame
+    // with a far ahead id can't make every real frame after it look stale
+    pub fn mark_rendered(&mut self, frame_id: u32) {
+        self.last_rendered_frame_id = Some(frame_id);
+        self.partial_frames
+            .retain(|partial_frame_id, _| is_newer_frame(*partial_frame_id, frame_id));
+    }
+}
+
+pub fn seal_datagram(
+    sid: &[u8; SID_LEN],
+    media_key: &[u8; MEDIA_KEY_LEN],
+    packet: &[u8],
+) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(SID_LEN + packet.len() + MEDIA_MAC_LEN);
+    datagram.extend(sid);
+    datagram.extend(packet);
+
+    let mac = datagram_mac(media_key, &datagram);
+    datagram.extend(mac);
+
+    datagram
+}
+
+pub fn datagram_sid(datagram: &[u8]) -> Option<[u8; SID_LEN]> {
+    datagram.get(..SID_LEN)?.try_into().ok()
+}
+
+// Checks the MAC over the SID and packet and returns the media packet it protects
+pub fn open_datagram<'a>(
+    media_key: &[u8; MEDIA_KEY_LEN],
+    datagram: &'a [u8],
+) -> Result<&'a [u8], Box<dyn Error + Send + Sync>> {
+    if datagram.len() < SID_LEN + MEDIA_MAC_LEN {
+        return Err("Media Error: datagram too short".into());
+    }
+
+    let (signed, mac) = datagram.split_at(datagram.len() - MEDIA_MAC_LEN);
+
+    let mut verifier = HmacSha256::new_from_slice(media_key)?;
+    verifier.update(signed);
+    verifier
+        .verify_truncated_left(mac)
+        .map_err(|_| "Media Error: bad MAC")?;
+
+    Ok(&signed[SID_LEN..])
+}
+
+fn datagram_mac(media_key: &[u8; MEDIA_KEY_LEN], signed: &[u8]) -> [u8; MEDIA_MAC_LEN] {
+    let mut signer =
+        HmacSha256::new_from_slice(media_key).expect("HMAC accepts keys of any length");
+    signer.update(signed);
+
+    let mut mac = [0u8; MEDIA_MAC_LEN];
+    mac.copy_from_slice(&signer.finalize().into_bytes()[..MEDIA_MAC_LEN]);
+    mac
+}
+
+pub struct ReplayWindow {
+    highest_sequence: Option<u32>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow {
+            highest_sequence: None,
+            seen: 0,
+        }
+    }
+
+    // Accepts each sequence number once, within a window of the last 64 packets
+    pub fn accept(&mut self, sequence: u32) -> bool {
+        let Some(highest_sequence) = self.highest_sequence else {
+            self.highest_sequence = Some(sequence);
+            self.seen = 1;
+            return true;
+        };
+
+        let ahead = sequence.wrapping_sub(highest_sequence) as i32;
+
+        if ahead > 0 {
+            self.seen = if ahead >= 64 { 0 } else { self.seen << ahead };
+            self.seen |= 1;
+            self.highest_sequence = Some(sequence);
+            return true;
+        }
+
+        let behind = -(ahead as i64);
+        if behind >= 64 || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(packets: &[Vec<u8>]) -> Vec<(MediaHeader, &[u8])> {
+        packets
+            .iter()
+            .map(|packet| MediaHeader::parse(packet).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_split_into_numbered_fragments() {
+        let frame: Vec<u8> = (0..MAX_FRAGMENT_PAYLOAD * 2 + 10)
+            .map(|i| i as u8)
+            .collect();
+        let mut next_sequence = u32::MAX;
+
+        let packets = fragment_frame(&frame, 7, &mut next_sequence, 0).unwrap();
+        let fragments = parse_all(&packets);
+
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(next_sequence, 2);
+
+        for (index, (header, _)) in fragments.iter().enumerate() {
+            assert_eq!(header.frame_id, 7);
+            assert_eq!(header.fragment_index, index as u16);
+            assert_eq!(header.fragment_count, 3);
+            assert_eq!(header.sequence, u32::MAX.wrapping_add(index as u32));
+        }
+
+        assert_eq!(fragments[2].1.len(), 10);
+    }
+
+    #[test]
+    fn empty_frames_still_send_one_fragment() {
+        let packets = fragment_frame(&[], 1, &mut 0, 0).unwrap();
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), MEDIA_HEADER_LEN);
+    }
+
+    #[test]
+    fn headers_with_bad_fragment_numbers_are_refused() {
+        let mut header = MediaHeader::parse(&control_packet(0, 0)).unwrap().0;
+
+        header.fragment_index = 1;
+        assert!(MediaHeader::parse(&header.to_bytes()).is_err());
+
+        header.fragment_count = 0;
+        header.fragment_index = 0;
+        assert!(MediaHeader::parse(&header.to_bytes()).is_err());
+
+        assert!(MediaHeader::parse(&header.to_bytes()[..MEDIA_HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn reassembler_puts_fragments_back_in_order() {
+        let frame: Vec<u8> = (0..MAX_FRAGMENT_PAYLOAD * 3)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let packets = fragment_frame(&frame, 1, &mut 0, 0).unwrap();
+        let fragments = parse_all(&packets);
+
+        let mut reassembler = FrameReassembler::new(DEFAULT_REASSEMBLY_TIMEOUT);
+
+        assert_eq!(reassembler.push(&fragments[2].0, fragments[2].1), None);
+        assert_eq!(reassembler.push(&fragments[0].0, fragments[0].1), None);
+        // A duplicate doesn't count towards completing the frame
+        assert_eq!(reassembler.push(&fragments[0].0, fragments[0].1), None);
+        assert_eq!(
+            reassembler.push(&fragments[1].0, fragments[1].1),
+            Some(frame)
+        );
+    }
+
+    #[test]
+    fn reassembler_drops_frames_older_than_the_last_rendered() {
+        let mut reassembler = FrameReassembler::new(DEFAULT_REASSEMBLY_TIMEOUT);
+
+        let newest = fragment_frame(b"new", u32::MAX, &mut 0, 0).unwrap();
+        let (header, payload) = MediaHeader::parse(&newest[0]).unwrap();
+
+        assert!(reassembler.push(&header, payload).is_some());
+
+        // Nothing is stale until the frame has been shown
+        let older = fragment_frame(b"old", u32::MAX - 1, &mut 0, 0).unwrap();
+        let (older_header, older_payload) = MediaHeader::parse(&older[0]).unwrap();
+
+        assert!(reassembler.push(&older_header, older_payload).is_some());
+
+        reassembler.mark_rendered(u32::MAX);
+
+        assert_eq!(reassembler.push(&older_header, older_payload), None);
+        assert_eq!(reassembler.push(&header, payload), None);
+
+        // Frame ids wrap around to zero and keep counting as newer
+        let wrapped = fragment_frame(b"wrapped", 0, &mut 0, 0).unwrap();
+        let (wrapped_header, wrapped_payload) = MediaHeader::parse(&wrapped[0]).unwrap();
+
+        assert_eq!(
+            reassembler.push(&wrapped_header, wrapped_payload),
+            Some(b"wrapped".to_vec())
+        );
+    }
+
+    #[test]
+    fn reassembler_forgets_partial_frames_after_the_timeout() {
+        let frame = vec![1; MAX_FRAGMENT_PAYLOAD + 1];
+        let packets = fragment_frame(&frame, 1, &mut 0, 0).unwrap();
+        let fragments = parse_all(&packets);
+
+        let mut reassembler = FrameReassembler::new(Duration::ZERO);
+
+        assert_eq!(reassembler.push(&fragments[0].0, fragments[0].1), None);
+        assert_eq!(reassembler.push(&fragments[1].0, fragments[1].1), None);
+    }
+
+    #[test]
+    fn sealed_datagrams_open_only_with_their_key() {
+        let sid = [1, 2, 3, 4];
+        let key = [5; MEDIA_KEY_LEN];
+        let packet = control_packet(MEDIA_FLAG_REGISTER, 9);
+
+        let datagram = seal_datagram(&sid, &key, &packet);
+
+        assert_eq!(datagram_sid(&datagram), Some(sid));
+        assert_eq!(open_datagram(&key, &datagram).unwrap(), packet.as_slice());
+        assert!(open_datagram(&[6; MEDIA_KEY_LEN], &datagram).is_err());
+
+        let mut tampered = datagram.clone();
+        tampered[SID_LEN] ^= 1;
+        assert!(open_datagram(&key, &tampered).is_err());
+
+        assert!(open_datagram(&key, &datagram[..SID_LEN + MEDIA_MAC_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn replay_window_accepts_each_sequence_once() {
+        let mut window = ReplayWindow::new();
+
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+        assert!(window.accept(12));
+        // Late but inside the window
+        assert!(window.accept(11));
+        assert!(!window.accept(11));
+        assert!(window.accept(9));
+    }
+
+    #[test]
+    fn replay_window_refuses_packets_older_than_64_behind() {
+        let mut window = ReplayWindow::new();
+
+        assert!(window.accept(100));
+        assert!(window.accept(163));
+        assert!(window.accept(101));
+        assert!(!window.accept(99));
+
+        // Jumping a whole window ahead forgets everything before it
+        assert!(window.accept(1000));
+        assert!(!window.accept(163));
+        assert!(window.accept(999));
+    }
+
+    #[test]
+    fn replay_window_follows_sequences_across_the_wrap() {
+        let mut window = ReplayWindow::new();
+
+        assert!(window.accept(u32::MAX - 1));
+        assert!(window.accept(1));
+        assert!(window.accept(u32::MAX));
+        assert!(window.accept(0));
+        assert!(!window.accept(u32::MAX - 1));
+        assert!(!window.accept(1));
+        assert!(window.accept(2));
+    }
+}
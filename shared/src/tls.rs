@@ -0,0 +1,161 @@
+use std::{error::Error, sync::Arc};
+
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use sha2::{Digest, Sha256};
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector, client::TlsStream, rustls};
+
+const CERT_FINGERPRINT_LEN: usize = 32;
+
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<Arc<ServerConfig>, Box<dyn Error + Send + Sync>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| format!("Failed to read certificate {}: {}", cert_path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to parse certificate {}: {}", cert_path, e))?;
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Failed to read private key {}: {}", key_path, e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+// A pinned certificate replaces chain validation entirely, a custom CA replaces
+// the bundled web roots, and with neither the server is verified normally.
+pub fn client_config(
+    ca_path: Option<&str>,
+    pinned_cert_sha256: Option<&str>,
+) -> Result<Arc<ClientConfig>, Box<dyn Error + Send + Sync>> {
+    if let Some(pin) = pinned_cert_sha256 {
+        let verifier = PinnedCertVerifier {
+            fingerprint: parse_fingerprint(pin)?,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        };
+
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+
+        return Ok(Arc::new(config));
+    }
+
+    let mut roots = RootCertStore::empty();
+
+    match ca_path {
+        Some(ca_path) => {
+            for cert in CertificateDer::pem_file_iter(ca_path)
+                .map_err(|e| format!("Failed to read CA {}: {}", ca_path, e))?
+            {
+                roots.add(cert.map_err(|e| format!("Failed to parse CA {}: {}", ca_path, e))?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+pub fn server_name(tcp_addr: &str) -> Result<ServerName<'static>, Box<dyn Error + Send + Sync>> {
+    let host = match tcp_addr.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => tcp_addr,
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    Ok(ServerName::try_from(host.to_string())?)
+}
+
+fn parse_fingerprint(
+    pin: &str,
+) -> Result<[u8; CERT_FINGERPRINT_LEN], Box<dyn Error + Send + Sync>> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+
+    if hex.len() != CERT_FINGERPRINT_LEN * 2 || !hex.is_ascii() {
+        return Err("Certificate pin must be a hex encoded SHA-256 fingerprint".into());
+    }
+
+    let mut fingerprint = [0; CERT_FINGERPRINT_LEN];
+
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| "Certificate pin must be a hex encoded SHA-256 fingerprint")?;
+    }
+
+    Ok(fingerprint)
+}
+
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: [u8; CERT_FINGERPRINT_LEN],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Server certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}